@@ -0,0 +1,82 @@
+//! Load, scroll and edit benchmarks on a large generated file.
+//!
+//! The file size defaults to 256 MB and can be changed with the
+//! `KILO_RS_BENCH_MB` environment variable.
+
+use std::{env, hint::black_box, io::Cursor};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use kilo_rs::{buffer::Buffer, syntax::HLDB};
+
+const SCREEN_ROWS: usize = 50;
+
+/// A log-like file of roughly `mb` megabytes with lines of varying length.
+fn generate(mb: usize) -> Vec<u8> {
+    let mut text = Vec::with_capacity(mb << 20);
+    let mut i = 0u64;
+    while text.len() < mb << 20 {
+        let line = format!(
+            "2024-01-01T00:00:{:02}Z\tINFO\trequest {} served in {} ms{}\n",
+            i % 60,
+            i,
+            i * 7 % 1000,
+            " padding".repeat((i % 16) as usize)
+        );
+        text.extend_from_slice(line.as_bytes());
+        i += 1;
+    }
+    text
+}
+
+fn file_size_mb() -> usize {
+    env::var("KILO_RS_BENCH_MB")
+        .ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(256)
+}
+
+fn bench_buffer(c: &mut Criterion) {
+    let text = generate(file_size_mb());
+    let mut group = c.benchmark_group("buffer");
+    group.sample_size(10);
+
+    group.bench_function("load", |b| {
+        b.iter(|| Buffer::from_reader(Cursor::new(black_box(&text)), 8).unwrap())
+    });
+
+    let mut buffer = Buffer::from_reader(Cursor::new(&text), 8).unwrap();
+    buffer.set_syntax(Some(HLDB[0]));
+    let rows = buffer.len();
+
+    // Page through the file a screen at a time, rendering and highlighting
+    // every visible row like `draw_rows` does.
+    group.bench_function("scroll", |b| {
+        b.iter(|| {
+            let mut top = 0;
+            for _ in 0..1000 {
+                for at in top..(top + SCREEN_ROWS).min(rows) {
+                    black_box(buffer.row(at).rsize);
+                }
+                top = (top + rows / 997) % rows;
+            }
+        })
+    });
+
+    group.bench_function("edit", |b| {
+        b.iter(|| {
+            for i in 0..1000 {
+                let at = i * 7919 % rows;
+                buffer.insert_char(at, 3, 'x');
+                buffer.split_row(at, 5);
+                buffer.join_rows(at);
+                buffer.del_char(at, 3);
+                black_box(buffer.row(at).rsize);
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_buffer);
+criterion_main!(benches);
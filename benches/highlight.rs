@@ -0,0 +1,41 @@
+//! Highlighting benchmarks on minified-style input: a few very long lines.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kilo_rs::{
+    buffer::Row,
+    syntax::{Highlighter, HLDB},
+};
+
+fn minified(len: usize) -> String {
+    let snippet =
+        "fn f(x:u32)->Option<String>{let s=\"a\\\"b\";if x>0x1f{return None}/*c*/vec![1.5,2]};";
+    snippet.chars().cycle().take(len).collect()
+}
+
+fn bench_highlight(c: &mut Criterion) {
+    let highlighter = Highlighter::new(HLDB[0]);
+    let mut group = c.benchmark_group("highlight");
+
+    for len in [10_000, 100_000, 1_000_000] {
+        let render = minified(len);
+        group.throughput(Throughput::Bytes(render.len() as u64));
+        group.bench_with_input(BenchmarkId::new("long_line", len), &render, |b, render| {
+            let mut row = Row {
+                rsize: render.chars().count(),
+                render: render.clone(),
+                hl: Vec::new(),
+            };
+            b.iter(|| {
+                highlighter.highlight(&mut row);
+                black_box(row.hl.len())
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_highlight);
+criterion_main!(benches);
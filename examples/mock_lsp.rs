@@ -0,0 +1,104 @@
+//! A stand-in language server for the tests of `kilo_rs::lsp`. It flags
+//! every `bad` in a document as a warning and gives canned answers to the
+//! requests the editor makes.
+
+use std::io::{self, BufReader};
+
+use kilo_rs::lsp::{read_message, utf16_col, write_message};
+use serde_json::{json, Value};
+
+fn main() -> io::Result<()> {
+    let mut reader = BufReader::new(io::stdin());
+    let mut stdout = io::stdout();
+    while let Some(message) = read_message(&mut reader)? {
+        let params = &message["params"];
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => json!({ "capabilities": { "textDocumentSync": 1 } }),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                publish(&mut stdout, &document["uri"], &document["text"])?;
+                continue;
+            }
+            "textDocument/didChange" => {
+                let text = &params["contentChanges"][0]["text"];
+                publish(&mut stdout, &params["textDocument"]["uri"], text)?;
+                continue;
+            }
+            "exit" => break,
+            _ if message.get("id").is_none() => continue,
+            "textDocument/hover" => {
+                json!({ "contents": { "kind": "markdown", "value": "```rust\nfn mock()\n```" } })
+            }
+            "textDocument/definition" => location(&params["textDocument"]["uri"], &json!(0), 0),
+            "textDocument/references" => {
+                let position = &params["position"];
+                json!([
+                    location(&params["textDocument"]["uri"], &json!(0), 0),
+                    location(
+                        &params["textDocument"]["uri"],
+                        &position["line"],
+                        position["character"].as_u64().unwrap(),
+                    ),
+                ])
+            }
+            "textDocument/rename" => {
+                let position = &params["position"];
+                let end = position["character"].as_u64().unwrap() + 3;
+                let edit = json!({
+                    "range": { "start": position, "end": { "line": position["line"], "character": end } },
+                    "newText": params["newName"],
+                });
+                let uri = params["textDocument"]["uri"].as_str().unwrap();
+                json!({ "changes": { uri: [edit] } })
+            }
+            "textDocument/completion" => json!({
+                "isIncomplete": false,
+                "items": [{ "label": "alpha" }, { "label": "beta", "insertText": "beta()" }],
+            }),
+            "shutdown" => Value::Null,
+            method => {
+                let error =
+                    json!({ "code": -32601, "message": format!("unknown method {method}") });
+                write_message(
+                    &mut stdout,
+                    &json!({ "jsonrpc": "2.0", "id": message["id"], "error": error }),
+                )?;
+                continue;
+            }
+        };
+        write_message(
+            &mut stdout,
+            &json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }),
+        )?;
+    }
+    Ok(())
+}
+
+fn location(uri: &Value, line: &Value, character: u64) -> Value {
+    let position = json!({ "line": line, "character": character });
+    json!({ "uri": uri, "range": { "start": position, "end": position } })
+}
+
+fn publish(stdout: &mut io::Stdout, uri: &Value, text: &Value) -> io::Result<()> {
+    let mut diagnostics = Vec::new();
+    for (line, row) in text.as_str().unwrap_or_default().lines().enumerate() {
+        for (i, _) in row.match_indices("bad") {
+            let start = utf16_col(row.chars(), row[..i].chars().count());
+            let range = json!({
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": start + 3 },
+            });
+            diagnostics.push(json!({
+                "range": range,
+                "severity": 2,
+                "source": "mock",
+                "message": "bad word",
+            }));
+        }
+    }
+    let params = json!({ "uri": uri, "diagnostics": diagnostics });
+    write_message(
+        stdout,
+        &json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": params }),
+    )
+}
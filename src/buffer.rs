@@ -0,0 +1,725 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+use ropey::{Rope, RopeBuilder, RopeSlice};
+
+use crate::syntax::{update_syntax, Highlight, Highlighter, Syntax};
+#[cfg(feature = "tree-sitter")]
+use crate::treesitter::{self, TreeSitter};
+
+/// Rendered rows kept around at once. Only rows that get drawn are rendered,
+/// so this just needs to comfortably hold a few screens worth of them.
+const MAX_CACHED_ROWS: usize = 1024;
+
+/// A row as it is drawn: tabs expanded to spaces and one highlight class per
+/// rendered character.
+pub struct Row {
+    pub render: String,
+    pub rsize: usize,
+    pub hl: Vec<Highlight>,
+}
+
+/// How the rows of a buffer are indented.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Indent {
+    /// No row is indented.
+    None,
+    Spaces,
+    Tabs,
+    /// Some rows use tabs and others spaces, or a row uses both.
+    Mixed,
+}
+
+/// Line terminator used when writing the file back.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// The text of a file, stored in a rope so that loading and editing large
+/// files stays cheap.
+///
+/// Every row ends with a `\n`, so a buffer of `n` rows is a rope of `n + 1`
+/// lines whose last line is empty. Positions within a row (`cx`) count chars.
+pub struct Buffer {
+    text: Rope,
+    highlighter: Option<Highlighter>,
+    /// Used instead of `highlighter` when the file type has a grammar.
+    #[cfg(feature = "tree-sitter")]
+    ts: Option<TreeSitter>,
+    tab_stop: usize,
+    line_ending: LineEnding,
+    cache: HashMap<usize, Row>,
+    /// Number of edits made, for telling whether the text changed since.
+    version: usize,
+}
+
+impl Buffer {
+    pub fn new(tab_stop: usize) -> Self {
+        Buffer {
+            text: Rope::new(),
+            highlighter: None,
+            #[cfg(feature = "tree-sitter")]
+            ts: None,
+            tab_stop,
+            line_ending: LineEnding::Lf,
+            cache: HashMap::new(),
+            version: 0,
+        }
+    }
+
+    /// Reads a whole file. `\r\n` line endings become `\n`, to be written
+    /// back as `\r\n` if the first line had one, and a missing newline at the
+    /// end of the last line is added.
+    pub fn from_reader<R: BufRead>(mut reader: R, tab_stop: usize) -> io::Result<Self> {
+        const CHUNK: usize = 64 * 1024;
+        let mut builder = RopeBuilder::new();
+        let mut line_ending = None;
+        let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK * 2);
+        let mut flush = |chunk: &mut Vec<u8>| -> io::Result<()> {
+            let s = std::str::from_utf8(chunk)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            builder.append(s);
+            chunk.clear();
+            Ok(())
+        };
+
+        loop {
+            let start = chunk.len();
+            if reader.read_until(b'\n', &mut chunk)? == 0 {
+                break;
+            }
+            if chunk.last() != Some(&b'\n') {
+                chunk.push(b'\n');
+            }
+            let crlf = chunk[start..].ends_with(b"\r\n");
+            if crlf {
+                chunk.remove(chunk.len() - 2);
+            }
+            line_ending.get_or_insert(if crlf {
+                LineEnding::Crlf
+            } else {
+                LineEnding::Lf
+            });
+            if chunk.len() >= CHUNK {
+                flush(&mut chunk)?;
+            }
+        }
+        flush(&mut chunk)?;
+
+        Ok(Buffer {
+            text: builder.finish(),
+            line_ending: line_ending.unwrap_or(LineEnding::Lf),
+            ..Buffer::new(tab_stop)
+        })
+    }
+
+    /// Number of rows.
+    pub fn len(&self) -> usize {
+        self.text.len_lines() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        self.text.len_bytes()
+    }
+
+    pub fn syntax(&self) -> Option<Syntax> {
+        self.highlighter.as_ref().map(|h| h.syntax)
+    }
+
+    pub fn set_syntax(&mut self, syntax: Option<Syntax>) {
+        self.highlighter = syntax.map(Highlighter::new);
+        #[cfg(feature = "tree-sitter")]
+        {
+            self.ts = syntax.and_then(|s| TreeSitter::new(s.filetype));
+        }
+        self.cache.clear();
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    pub fn tab_stop(&self) -> usize {
+        self.tab_stop
+    }
+
+    pub fn set_tab_stop(&mut self, tab_stop: usize) {
+        self.tab_stop = tab_stop;
+        self.cache.clear();
+    }
+
+    /// Contents of row `at`, without its newline. The row past the last one,
+    /// where the cursor can be, is empty.
+    pub fn line(&self, at: usize) -> RopeSlice<'_> {
+        let line = self.text.line(at);
+        line.slice(..line.len_chars().saturating_sub(1))
+    }
+
+    /// Number of chars in row `at`.
+    pub fn line_len(&self, at: usize) -> usize {
+        self.text.line(at).len_chars().saturating_sub(1)
+    }
+
+    /// Iterates over the contents of every row.
+    pub fn lines(&self) -> impl Iterator<Item = RopeSlice<'_>> {
+        (0..self.len()).map(|at| self.line(at))
+    }
+
+    pub fn cx_to_rx(&self, at: usize, cx: usize) -> usize {
+        let mut rx = 0;
+        for c in self.line(at).chars().take(cx) {
+            if c == '\t' {
+                rx += (self.tab_stop - 1) - (rx % self.tab_stop);
+            }
+            rx += 1;
+        }
+        rx
+    }
+
+    pub fn rx_to_cx(&self, at: usize, rx: usize) -> usize {
+        let mut cur_rx = 0;
+        let mut ret_cx = 0;
+        for (cx, c) in self.line(at).chars().enumerate() {
+            if c == '\t' {
+                cur_rx += (self.tab_stop - 1) - (cur_rx % self.tab_stop);
+            }
+            ret_cx = cx;
+            cur_rx += 1;
+            if cur_rx > rx {
+                return cx;
+            }
+        }
+        ret_cx
+    }
+
+    /// Row `at` with tabs expanded, without highlighting.
+    pub fn render(&self, at: usize) -> String {
+        let mut render = String::new();
+        let mut idx = 0;
+        for c in self.line(at).chars() {
+            if c == '\t' {
+                render.push(' ');
+                idx += 1;
+                while idx % self.tab_stop != 0 {
+                    render.push(' ');
+                    idx += 1;
+                }
+            } else {
+                render.push(c);
+                idx += 1;
+            }
+        }
+        render
+    }
+
+    /// Row `at` rendered and highlighted, computed on first use.
+    pub fn row(&mut self, at: usize) -> &Row {
+        if self.cache.len() >= MAX_CACHED_ROWS && !self.cache.contains_key(&at) {
+            self.cache.clear();
+        }
+        if !self.cache.contains_key(&at) {
+            let render = self.render(at);
+            let mut row = Row {
+                rsize: render.chars().count(),
+                render,
+                hl: Vec::new(),
+            };
+            #[cfg(feature = "tree-sitter")]
+            let hl = self
+                .ts
+                .as_mut()
+                .and_then(|ts| ts.highlight_line(&self.text, at));
+            #[cfg(not(feature = "tree-sitter"))]
+            let hl: Option<Vec<Highlight>> = None;
+            match hl {
+                Some(hl) => row.hl = self.expand_tabs(at, &hl),
+                None => update_syntax(self.highlighter.as_ref(), &mut row),
+            }
+            self.cache.insert(at, row);
+        }
+        &self.cache[&at]
+    }
+
+    /// Repeats the highlight of every char of row `at` over the columns it
+    /// renders to.
+    fn expand_tabs(&self, at: usize, hl: &[Highlight]) -> Vec<Highlight> {
+        let mut render_hl = Vec::with_capacity(hl.len());
+        for (c, &hl) in self.line(at).chars().zip(hl) {
+            render_hl.push(hl);
+            if c == '\t' {
+                while render_hl.len() % self.tab_stop != 0 {
+                    render_hl.push(hl);
+                }
+            }
+        }
+        render_hl
+    }
+
+    /// Row and render column of the bracket matching the one at render column
+    /// `rx` of row `at`, looking at most `limit` rows away. Brackets inside
+    /// strings and comments are skipped.
+    pub fn matching_bracket(
+        &mut self,
+        at: usize,
+        rx: usize,
+        limit: usize,
+    ) -> Option<(usize, usize)> {
+        let is_code = |hl: Highlight| !matches!(hl, Highlight::String | Highlight::Comment);
+        let row = self.row(at);
+        let c = row.render.chars().nth(rx)?;
+        if !is_code(row.hl[rx]) {
+            return None;
+        }
+        let (open, close, forward) = match c {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => (')', '(', false),
+            ']' => (']', '[', false),
+            '}' => ('}', '{', false),
+            _ => return None,
+        };
+        let rows: Box<dyn Iterator<Item = usize>> = if forward {
+            Box::new(at..self.len().min(at + limit + 1))
+        } else {
+            Box::new((at.saturating_sub(limit)..=at).rev())
+        };
+
+        let mut depth = 0;
+        for r in rows {
+            let row = self.row(r);
+            let mut cols: Vec<(usize, char)> = row
+                .render
+                .chars()
+                .enumerate()
+                .filter(|&(x, _)| is_code(row.hl[x]))
+                .collect();
+            if !forward {
+                cols.reverse();
+            }
+            for (x, ch) in cols {
+                if r == at && (if forward { x < rx } else { x > rx }) {
+                    continue;
+                }
+                if ch == open {
+                    depth += 1;
+                } else if ch == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((r, x));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Offset in chars from the start of the buffer to column `cx` of row
+    /// `at`, or to the end of the buffer if `at` is past the last row.
+    pub fn char_idx(&self, at: usize, cx: usize) -> usize {
+        if at >= self.len() {
+            return self.text.len_chars();
+        }
+        self.text.line_to_char(at) + cx.min(self.line_len(at))
+    }
+
+    /// Replaces the chars in `range` with `s`. Every edit goes through here so
+    /// that the syntax tree can follow along.
+    fn replace(&mut self, range: Range<usize>, s: &str) {
+        #[cfg(feature = "tree-sitter")]
+        let old = self.ts.as_ref().map(|_| {
+            (
+                treesitter::position(&self.text, range.start),
+                treesitter::position(&self.text, range.end),
+            )
+        });
+        if !range.is_empty() {
+            self.text.remove(range.clone());
+        }
+        self.text.insert(range.start, s);
+        self.version += 1;
+        #[cfg(feature = "tree-sitter")]
+        if let Some((start, old_end)) = old {
+            let new_end = treesitter::position(&self.text, range.start + s.chars().count());
+            if let Some(ts) = &mut self.ts {
+                ts.edit(start, old_end, new_end);
+            }
+            // The edit may have opened or closed a comment or string that
+            // spans other rows.
+            self.cache.clear();
+        }
+    }
+
+    pub fn insert_row(&mut self, at: usize, s: &str) {
+        if at > self.len() {
+            return;
+        }
+        let idx = self.text.line_to_char(at);
+        self.replace(idx..idx, &format!("{s}\n"));
+        self.cache.clear();
+    }
+
+    pub fn del_row(&mut self, at: usize) {
+        if at >= self.len() {
+            return;
+        }
+        let start = self.text.line_to_char(at);
+        let end = self.text.line_to_char(at + 1);
+        self.replace(start..end, "");
+        self.cache.clear();
+    }
+
+    pub fn insert_char(&mut self, at: usize, cx: usize, c: char) {
+        let idx = self.char_idx(at, cx);
+        self.replace(idx..idx, c.encode_utf8(&mut [0; 4]));
+        if c == '\n' {
+            self.cache.clear();
+        } else {
+            self.cache.remove(&at);
+        }
+    }
+
+    pub fn insert_str(&mut self, at: usize, cx: usize, s: &str) {
+        let idx = self.char_idx(at, cx);
+        self.replace(idx..idx, s);
+        self.cache.clear();
+    }
+
+    pub fn del_char(&mut self, at: usize, cx: usize) {
+        if cx >= self.line_len(at) {
+            return;
+        }
+        let idx = self.char_idx(at, cx);
+        self.replace(idx..idx + 1, "");
+        self.cache.remove(&at);
+    }
+
+    /// Number of non-whitespace chars before column `cx` of row `at`.
+    pub fn nonblank_before(&self, at: usize, cx: usize) -> usize {
+        self.text
+            .slice(..self.char_idx(at, cx))
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .count()
+    }
+
+    /// Row and column of the non-whitespace char that has `n` others before
+    /// it, or the end of the buffer if there are fewer.
+    pub fn nth_nonblank(&self, n: usize) -> (usize, usize) {
+        let mut seen = 0;
+        for at in 0..self.len() {
+            for (cx, c) in self.line(at).chars().enumerate() {
+                if !c.is_whitespace() {
+                    if seen == n {
+                        return (at, cx);
+                    }
+                    seen += 1;
+                }
+            }
+        }
+        (self.len(), 0)
+    }
+
+    /// Text between two `(row, column)` positions.
+    pub fn text_between(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let range = self.char_idx(start.0, start.1)..self.char_idx(end.0, end.1);
+        self.text.slice(range).to_string()
+    }
+
+    /// Replaces the text between two `(row, column)` positions with `s`. A
+    /// newline is added if `s` would otherwise end the buffer without one.
+    pub fn replace_between(&mut self, start: (usize, usize), end: (usize, usize), s: &str) {
+        let range = self.char_idx(start.0, start.1)..self.char_idx(end.0, end.1);
+        if range.end == self.text.len_chars() && !s.is_empty() && !s.ends_with('\n') {
+            self.replace(range, &format!("{s}\n"));
+        } else {
+            self.replace(range, s);
+        }
+        self.cache.clear();
+    }
+
+    /// Splits row `at` in two at `cx`.
+    pub fn split_row(&mut self, at: usize, cx: usize) {
+        self.insert_char(at, cx, '\n');
+    }
+
+    /// Appends row `at + 1` to row `at`.
+    pub fn join_rows(&mut self, at: usize) {
+        if at + 1 >= self.len() {
+            return;
+        }
+        let idx = self.text.line_to_char(at + 1) - 1;
+        self.replace(idx..idx + 1, "");
+        self.cache.clear();
+    }
+
+    /// Works out whether rows are indented with tabs or spaces.
+    pub fn indentation(&self) -> Indent {
+        let (mut spaces, mut tabs) = (false, false);
+        for line in self.lines() {
+            let (mut s, mut t) = (false, false);
+            for c in line.chars() {
+                match c {
+                    ' ' => s = true,
+                    '\t' => t = true,
+                    _ => break,
+                }
+            }
+            if s && t {
+                return Indent::Mixed;
+            }
+            spaces |= s;
+            tabs |= t;
+            if spaces && tabs {
+                return Indent::Mixed;
+            }
+        }
+        match (spaces, tabs) {
+            (true, _) => Indent::Spaces,
+            (_, true) => Indent::Tabs,
+            _ => Indent::None,
+        }
+    }
+
+    /// Removes spaces and tabs from the end of every row, returning the
+    /// number of rows changed.
+    pub fn strip_trailing_whitespace(&mut self) -> usize {
+        let mut changed = 0;
+        for at in 0..self.len() {
+            let len = self.line_len(at);
+            let line = self.line(at);
+            let trailing = (0..len)
+                .rev()
+                .take_while(|&cx| matches!(line.char(cx), ' ' | '\t'))
+                .count();
+            if trailing > 0 {
+                let start = self.text.line_to_char(at) + len - trailing;
+                self.replace(start..start + trailing, "");
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.cache.clear();
+        }
+        changed
+    }
+
+    /// Writes every row terminated by the buffer's line ending, returning the
+    /// number of bytes written.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<usize> {
+        match self.line_ending {
+            LineEnding::Lf => {
+                self.text.write_to(writer)?;
+                Ok(self.text.len_bytes())
+            }
+            LineEnding::Crlf => {
+                for chunk in self.text.chunks() {
+                    for (i, part) in chunk.split('\n').enumerate() {
+                        if i > 0 {
+                            writer.write_all(b"\r\n")?;
+                        }
+                        writer.write_all(part.as_bytes())?;
+                    }
+                }
+                writer.flush()?;
+                Ok(self.text.len_bytes() + self.len())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.text.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        Buffer::from_reader(text.as_bytes(), 4).unwrap()
+    }
+
+    fn written(buffer: &Buffer) -> String {
+        let mut out = Vec::new();
+        let n = buffer.write_to(&mut out).unwrap();
+        assert_eq!(n, out.len());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn keeps_line_endings() {
+        let lf = buffer("a\nb\n");
+        assert!(lf.line_ending() == LineEnding::Lf);
+        assert_eq!(written(&lf), "a\nb\n");
+
+        let crlf = buffer("a\r\nb\r\n");
+        assert!(crlf.line_ending() == LineEnding::Crlf);
+        assert_eq!(crlf.to_string(), "a\nb\n");
+        assert_eq!(written(&crlf), "a\r\nb\r\n");
+    }
+
+    #[test]
+    fn adds_a_missing_final_newline() {
+        let buffer = buffer("a\nb");
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(written(&buffer), "a\nb\n");
+        assert_eq!(written(&self::buffer("a\r\nb")), "a\r\nb\r\n");
+        assert!(self::buffer("").is_empty());
+    }
+
+    #[test]
+    fn the_row_past_the_end_is_empty() {
+        let buffer = buffer("ab\n");
+        assert_eq!(buffer.line(1).len_chars(), 0);
+        assert_eq!(buffer.line_len(1), 0);
+        assert_eq!(Buffer::new(4).line_len(0), 0);
+    }
+
+    #[test]
+    fn indexes_chars() {
+        let buffer = buffer("ab\ncé\n");
+        assert_eq!(buffer.char_idx(0, 1), 1);
+        assert_eq!(buffer.char_idx(1, 2), 5);
+        // Columns past the end of a row stop at its end.
+        assert_eq!(buffer.char_idx(0, 9), 2);
+        assert_eq!(buffer.char_idx(2, 0), 6);
+        assert_eq!(buffer.char_idx(5, 3), 6);
+    }
+
+    #[test]
+    fn converts_columns_across_tabs() {
+        let buffer = buffer("\tab\ta\n");
+        assert_eq!(buffer.render(0), "    ab  a");
+        assert_eq!(buffer.cx_to_rx(0, 1), 4);
+        assert_eq!(buffer.cx_to_rx(0, 4), 8);
+        assert_eq!(buffer.rx_to_cx(0, 0), 0);
+        // Columns inside a tab belong to it.
+        assert_eq!(buffer.rx_to_cx(0, 2), 0);
+        assert_eq!(buffer.rx_to_cx(0, 5), 2);
+        assert_eq!(buffer.rx_to_cx(0, 7), 3);
+        assert_eq!(buffer.rx_to_cx(0, 8), 4);
+        for cx in 0..=4 {
+            assert_eq!(buffer.rx_to_cx(0, buffer.cx_to_rx(0, cx)), cx.min(4));
+        }
+    }
+
+    #[test]
+    fn cached_rows_follow_edits() {
+        let mut buffer = buffer("a\nb\nc\n");
+        let rows = |buffer: &mut Buffer| -> Vec<String> {
+            (0..buffer.len())
+                .map(|at| buffer.row(at).render.clone())
+                .collect()
+        };
+        assert_eq!(rows(&mut buffer), ["a", "b", "c"]);
+
+        buffer.insert_row(1, "x");
+        assert_eq!(rows(&mut buffer), ["a", "x", "b", "c"]);
+        buffer.del_row(0);
+        assert_eq!(rows(&mut buffer), ["x", "b", "c"]);
+        buffer.split_row(0, 0);
+        assert_eq!(rows(&mut buffer), ["", "x", "b", "c"]);
+        buffer.join_rows(1);
+        assert_eq!(rows(&mut buffer), ["", "xb", "c"]);
+        buffer.insert_char(2, 1, 'd');
+        buffer.del_char(1, 0);
+        assert_eq!(rows(&mut buffer), ["", "b", "cd"]);
+        assert_eq!(buffer.to_string(), "\nb\ncd\n");
+    }
+
+    fn rust(text: &str) -> Buffer {
+        let mut buffer = buffer(text);
+        buffer.set_syntax(Some(crate::syntax::HLDB[0]));
+        buffer
+    }
+
+    #[test]
+    fn matches_nested_brackets() {
+        let mut buffer = rust("f(a[b](c))\n");
+        assert_eq!(buffer.matching_bracket(0, 1, 10), Some((0, 9)));
+        assert_eq!(buffer.matching_bracket(0, 9, 10), Some((0, 1)));
+        assert_eq!(buffer.matching_bracket(0, 3, 10), Some((0, 5)));
+        assert_eq!(buffer.matching_bracket(0, 8, 10), Some((0, 6)));
+        assert_eq!(buffer.matching_bracket(0, 0, 10), None);
+        assert_eq!(rust("f(a\n").matching_bracket(0, 1, 10), None);
+        // Columns are render columns.
+        assert_eq!(rust("\t(x)\n").matching_bracket(0, 4, 10), Some((0, 6)));
+    }
+
+    #[test]
+    fn skips_brackets_in_strings_and_comments() {
+        let mut buffer = rust("f(\")\", // )\n  ')', x)\n");
+        assert_eq!(buffer.matching_bracket(0, 1, 10), Some((1, 8)));
+        assert_eq!(buffer.matching_bracket(1, 8, 10), Some((0, 1)));
+        // Nor is a bracket in a string matched with anything.
+        assert_eq!(buffer.matching_bracket(0, 3, 10), None);
+    }
+
+    #[test]
+    fn looks_for_brackets_within_the_limit() {
+        let mut buffer = rust("{\n\n\n}\n");
+        assert_eq!(buffer.matching_bracket(0, 0, 2), None);
+        assert_eq!(buffer.matching_bracket(0, 0, 3), Some((3, 0)));
+        assert_eq!(buffer.matching_bracket(3, 0, 2), None);
+        assert_eq!(buffer.matching_bracket(3, 0, 3), Some((0, 0)));
+    }
+
+    #[test]
+    fn strips_trailing_whitespace() {
+        let mut buffer = buffer("a \nb\t\n \t\nc\n\u{a0}\n");
+        buffer.row(0);
+        assert_eq!(buffer.strip_trailing_whitespace(), 3);
+        assert_eq!(buffer.to_string(), "a\nb\n\nc\n\u{a0}\n");
+        assert_eq!(buffer.row(0).render, "a");
+        assert_eq!(buffer.strip_trailing_whitespace(), 0);
+    }
+
+    #[test]
+    fn detects_indentation() {
+        let indent = |text| buffer(text).indentation();
+        assert!(indent("") == Indent::None);
+        assert!(indent("a\tb\nc  \n") == Indent::None);
+        assert!(indent("a\n  b\n") == Indent::Spaces);
+        assert!(indent("a\n\tb\n\t\tc\n") == Indent::Tabs);
+        assert!(indent("  a\n\tb\n") == Indent::Mixed);
+        assert!(indent("\t  a\n") == Indent::Mixed);
+    }
+
+    #[test]
+    fn replaces_between_positions() {
+        let mut buffer = buffer("one\ntwo\nthree\n");
+        buffer.replace_between((0, 1), (2, 2), "X");
+        assert_eq!(buffer.to_string(), "oXree\n");
+
+        // Text replacing the end of the buffer gets the final newline back.
+        buffer.replace_between((0, 0), (1, 0), "abc");
+        assert_eq!(buffer.to_string(), "abc\n");
+        assert_eq!(buffer.text_between((0, 1), (1, 0)), "bc\n");
+        // Unless there is no text left at all.
+        buffer.replace_between((0, 0), (1, 0), "");
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Command line arguments.
+
+use std::path::{Path, PathBuf};
+
+use crate::KILO_RS_VERSION;
+
+pub const USAGE: &str = "\
+Usage: kilo_rs [OPTIONS] [FILE...]
+
+Arguments:
+  FILE                 file to edit, `-` for standard input; FILE:LINE[:COL]
+                       and a preceding +LINE open it at that position
+
+Options:
+  -R, --view, --readonly
+                       open files read-only
+      --config PATH    read settings from PATH instead of the default config
+      --syntax FT      highlight files as file type FT (e.g. rust)
+  -h, --help           print this help
+  -V, --version        print the version";
+
+/// A file to edit and where to put the cursor in it. Lines and columns count
+/// from 1.
+#[derive(Clone)]
+pub struct FileArg {
+    /// Path of the file, `-` for standard input.
+    pub path: String,
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Args {
+    pub files: Vec<FileArg>,
+    pub read_only: bool,
+    pub config: Option<PathBuf>,
+    pub syntax: Option<String>,
+}
+
+pub enum Action {
+    Edit(Args),
+    Help,
+    Version,
+}
+
+pub fn version() -> String {
+    format!("kilo_rs {KILO_RS_VERSION}")
+}
+
+pub fn parse(mut argv: impl Iterator<Item = String>) -> Result<Action, String> {
+    let mut args = Args::default();
+    let mut line = None;
+    let mut only_files = false;
+    while let Some(arg) = argv.next() {
+        if only_files || arg == "-" || !arg.starts_with(['-', '+']) {
+            args.files.push(file_arg(arg, line.take()));
+            continue;
+        }
+        if let Some(n) = arg.strip_prefix('+') {
+            match n.parse::<usize>() {
+                Ok(n) => line = Some(n),
+                Err(_) => return Err(format!("invalid line number `{arg}`")),
+            }
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| {
+            value
+                .clone()
+                .or_else(|| argv.next())
+                .ok_or_else(|| format!("{name} needs a value"))
+        };
+        match name {
+            "--" => only_files = true,
+            "-h" | "--help" => return Ok(Action::Help),
+            "-V" | "--version" => return Ok(Action::Version),
+            "-R" | "--view" | "--readonly" => args.read_only = true,
+            "--config" => args.config = Some(PathBuf::from(value(name)?)),
+            "--syntax" => args.syntax = Some(value(name)?),
+            _ => return Err(format!("unknown option `{arg}`")),
+        }
+    }
+    if let Some(line) = line {
+        return Err(format!("+{line} must come before a file"));
+    }
+    Ok(Action::Edit(args))
+}
+
+/// Splits a `:LINE` or `:LINE:COL` suffix off `path`, unless a file by the
+/// whole name exists.
+fn file_arg(path: String, line: Option<usize>) -> FileArg {
+    if path != "-" && !Path::new(&path).exists() {
+        if let Some((rest, n)) = split_number(&path) {
+            return match split_number(rest) {
+                Some((file, line)) => FileArg {
+                    path: file.to_string(),
+                    line: Some(line),
+                    col: Some(n),
+                },
+                None => FileArg {
+                    path: rest.to_string(),
+                    line: Some(n),
+                    col: None,
+                },
+            };
+        }
+    }
+    FileArg {
+        path,
+        line,
+        col: None,
+    }
+}
+
+/// Splits `NAME:N` into `NAME` and `N`.
+fn split_number(s: &str) -> Option<(&str, usize)> {
+    let (name, n) = s.rsplit_once(':')?;
+    let n = n.parse().ok()?;
+    (!name.is_empty()).then_some((name, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(argv: &[&str]) -> Args {
+        match parse(argv.iter().map(|a| a.to_string())) {
+            Ok(Action::Edit(args)) => args,
+            Ok(_) => panic!("not an edit: {argv:?}"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn files(argv: &[&str]) -> Vec<(String, Option<usize>, Option<usize>)> {
+        edit(argv)
+            .files
+            .into_iter()
+            .map(|f| (f.path, f.line, f.col))
+            .collect()
+    }
+
+    fn error(argv: &[&str]) -> String {
+        parse(argv.iter().map(|a| a.to_string())).err().unwrap()
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(
+            files(&["+3", "none/a.rs", "none/b.rs"]),
+            [
+                ("none/a.rs".to_string(), Some(3), None),
+                ("none/b.rs".to_string(), None, None)
+            ]
+        );
+        assert_eq!(
+            files(&["none/a.rs:7", "none/b.rs:7:2"]),
+            [
+                ("none/a.rs".to_string(), Some(7), None),
+                ("none/b.rs".to_string(), Some(7), Some(2))
+            ]
+        );
+        // The suffix wins over `+LINE`, and a name ending in `:` is kept.
+        assert_eq!(
+            files(&["+1", "none/a.rs:9", "none/c:"]),
+            [
+                ("none/a.rs".to_string(), Some(9), None),
+                ("none/c:".to_string(), None, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn existing_names_with_colons_are_kept() {
+        let dir = std::env::temp_dir().join(format!("kilo_rs-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes:2").to_string_lossy().into_owned();
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(files(&[&path]), [(path.clone(), None, None)]);
+    }
+
+    #[test]
+    fn stdin_and_files_after_dashes() {
+        assert_eq!(files(&["-"]), [("-".to_string(), None, None)]);
+        assert_eq!(
+            files(&["--", "-R", "+2"]),
+            [
+                ("-R".to_string(), None, None),
+                ("+2".to_string(), None, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn options() {
+        for flag in ["-R", "--view", "--readonly"] {
+            assert!(edit(&[flag]).read_only);
+        }
+        assert!(!edit(&["a.rs"]).read_only);
+        let args = edit(&["--config", "my.toml", "--syntax=rust"]);
+        assert_eq!(args.config, Some(PathBuf::from("my.toml")));
+        assert_eq!(args.syntax.as_deref(), Some("rust"));
+        let args = edit(&["--config=other.toml", "--syntax", "c"]);
+        assert_eq!(args.config, Some(PathBuf::from("other.toml")));
+        assert_eq!(args.syntax.as_deref(), Some("c"));
+        assert!(matches!(
+            parse(["-h".to_string()].into_iter()),
+            Ok(Action::Help)
+        ));
+        assert!(matches!(
+            parse(["--version".to_string()].into_iter()),
+            Ok(Action::Version)
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(&["--frobnicate"]), "unknown option `--frobnicate`");
+        assert_eq!(error(&["-x", "a.rs"]), "unknown option `-x`");
+        assert_eq!(error(&["--config"]), "--config needs a value");
+        assert_eq!(error(&["+x", "a.rs"]), "invalid line number `+x`");
+        assert_eq!(error(&["a.rs", "+4"]), "+4 must come before a file");
+    }
+}
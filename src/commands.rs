@@ -0,0 +1,1204 @@
+use std::{
+    fs,
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossterm::event::KeyCode;
+
+use kilo_rs::{buffer::Buffer, fold::fold_ranges, lsp};
+
+use crate::{
+    char_at, confirm_discard, del_char, events, file_changed, find, fold_end, goto_location,
+    hidden_by, insert_newline, lsp_open, lsp_request, move_cursor, open, open_arg, page_move,
+    project_root, prompt, quit, refuse_edit, save, selection, set_buffer, set_status_msg, settings,
+    shell, suspend, theme, write_backed_up, write_file, EditorConfig, Stash,
+};
+
+/// What kind of argument a command takes, used for completion in the
+/// command prompt.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Arg {
+    None,
+    Required,
+    /// An argument that can be left out, with nothing to complete.
+    Optional,
+    Path,
+    Setting,
+    Command,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub arg: Arg,
+    pub help: &'static str,
+    pub run: fn(&mut EditorConfig, &str) -> Result<()>,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "move-left",
+        arg: Arg::None,
+        help: "move the cursor left",
+        run: |config, _| {
+            move_cursor(config, KeyCode::Left);
+            Ok(())
+        },
+    },
+    Command {
+        name: "move-right",
+        arg: Arg::None,
+        help: "move the cursor right",
+        run: |config, _| {
+            move_cursor(config, KeyCode::Right);
+            Ok(())
+        },
+    },
+    Command {
+        name: "move-up",
+        arg: Arg::None,
+        help: "move the cursor up",
+        run: |config, _| {
+            move_cursor(config, KeyCode::Up);
+            Ok(())
+        },
+    },
+    Command {
+        name: "move-down",
+        arg: Arg::None,
+        help: "move the cursor down",
+        run: |config, _| {
+            move_cursor(config, KeyCode::Down);
+            Ok(())
+        },
+    },
+    Command {
+        name: "page-up",
+        arg: Arg::None,
+        help: "scroll one screen up",
+        run: |config, _| {
+            page_move(config, KeyCode::PageUp);
+            Ok(())
+        },
+    },
+    Command {
+        name: "page-down",
+        arg: Arg::None,
+        help: "scroll one screen down",
+        run: |config, _| {
+            page_move(config, KeyCode::PageDown);
+            Ok(())
+        },
+    },
+    Command {
+        name: "line-start",
+        arg: Arg::None,
+        help: "move to the start of the line",
+        run: |config, _| {
+            config.cx = 0;
+            Ok(())
+        },
+    },
+    Command {
+        name: "line-end",
+        arg: Arg::None,
+        help: "move to the end of the line",
+        run: |config, _| {
+            if config.cy < config.buffer.len() {
+                config.cx = config.buffer.line_len(config.cy);
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "newline",
+        arg: Arg::None,
+        help: "split the line at the cursor",
+        run: |config, _| insert_newline(config),
+    },
+    Command {
+        name: "delete-back",
+        arg: Arg::None,
+        help: "delete the character before the cursor",
+        run: |config, _| del_char(config),
+    },
+    Command {
+        name: "toggle-mark",
+        arg: Arg::None,
+        help: "start selecting at the cursor, or clear the selection",
+        run: |config, _| {
+            config.mark = match config.mark {
+                Some(_) => None,
+                None => Some((config.cy, config.cx)),
+            };
+            Ok(())
+        },
+    },
+    Command {
+        name: "save",
+        arg: Arg::None,
+        help: "write the buffer to its file",
+        run: |config, _| save(config),
+    },
+    Command {
+        name: "save-as",
+        arg: Arg::Path,
+        help: "write the buffer to another file",
+        run: save_as,
+    },
+    Command {
+        name: "sudo-save",
+        arg: Arg::Path,
+        help: "write the buffer with `sudo tee`, to the file or another path",
+        run: sudo_save,
+    },
+    Command {
+        name: "toggle-read-only",
+        arg: Arg::None,
+        help: "allow or refuse edits to the buffer",
+        run: |config, _| {
+            config.read_only = !config.read_only;
+            let msg = if config.read_only {
+                "Buffer is read-only"
+            } else {
+                "Buffer is editable"
+            };
+            set_status_msg(config, msg.to_string())
+        },
+    },
+    Command {
+        name: "toggle-autosave",
+        arg: Arg::None,
+        help: "turn saving the buffer once idle or unfocused on or off",
+        run: |config, _| {
+            config.autosave = !config.autosave;
+            let msg = match (config.autosave, config.read_only) {
+                (false, _) => "Autosave off",
+                (true, false) => "Autosave on",
+                (true, true) => "Autosave on, once the buffer is editable",
+            };
+            set_status_msg(config, msg.to_string())
+        },
+    },
+    Command {
+        name: "toggle-backup",
+        arg: Arg::None,
+        help: "turn backing the file up before saving on or off",
+        run: |config, _| {
+            config.backup = !config.backup;
+            let msg = if config.backup {
+                "Backups on"
+            } else {
+                "Backups off"
+            };
+            set_status_msg(config, msg.to_string())
+        },
+    },
+    Command {
+        name: "open",
+        arg: Arg::Path,
+        help: "replace the buffer with a file",
+        run: open_file,
+    },
+    Command {
+        name: "next-file",
+        arg: Arg::None,
+        help: "open the next file given on the command line",
+        run: |config, _| switch_file(config, 1),
+    },
+    Command {
+        name: "prev-file",
+        arg: Arg::None,
+        help: "open the previous file given on the command line",
+        run: |config, _| switch_file(config, -1),
+    },
+    Command {
+        name: "filter",
+        arg: Arg::Required,
+        help: "replace the selection, or the whole buffer, with its output through a shell command",
+        run: filter,
+    },
+    Command {
+        name: "insert-output",
+        arg: Arg::Required,
+        help: "insert the output of a shell command at the cursor",
+        run: insert_output,
+    },
+    Command {
+        name: "run",
+        arg: Arg::Required,
+        help: "run a shell command and show its output in a scratch buffer",
+        run: run_shell,
+    },
+    Command {
+        name: "close-output",
+        arg: Arg::None,
+        help: "drop the output of `run` and go back to where you were in the file",
+        run: close_output,
+    },
+    Command {
+        name: "build",
+        arg: Arg::Optional,
+        help: "run the build command, or CMD, in the background and collect diagnostics",
+        run: build,
+    },
+    Command {
+        name: "next-error",
+        arg: Arg::None,
+        help: "jump to the next diagnostic of the last build",
+        run: |config, _| goto_diagnostic(config, 1),
+    },
+    Command {
+        name: "prev-error",
+        arg: Arg::None,
+        help: "jump to the previous diagnostic of the last build",
+        run: |config, _| goto_diagnostic(config, -1),
+    },
+    Command {
+        name: "hover",
+        arg: Arg::None,
+        help: "show what the language server knows about the symbol under the cursor",
+        run: hover,
+    },
+    Command {
+        name: "goto-definition",
+        arg: Arg::None,
+        help: "jump to where the symbol under the cursor is defined",
+        run: goto_definition,
+    },
+    Command {
+        name: "find-references",
+        arg: Arg::None,
+        help: "pick one of the places the symbol under the cursor is used",
+        run: find_references,
+    },
+    Command {
+        name: "rename",
+        arg: Arg::Optional,
+        help: "rename the symbol under the cursor to NAME in every file",
+        run: rename,
+    },
+    Command {
+        name: "complete",
+        arg: Arg::None,
+        help: "complete the word before the cursor with the language server",
+        run: complete_word,
+    },
+    Command {
+        name: "goto",
+        arg: Arg::Required,
+        help: "jump to a line number",
+        run: goto,
+    },
+    Command {
+        name: "match-bracket",
+        arg: Arg::None,
+        help: "jump to the bracket matching the one under the cursor",
+        run: match_bracket,
+    },
+    Command {
+        name: "fold-toggle",
+        arg: Arg::None,
+        help: "fold the block around the cursor, or unfold it",
+        run: fold_toggle,
+    },
+    Command {
+        name: "fold-all",
+        arg: Arg::None,
+        help: "fold every block",
+        run: |config, _| {
+            config.folds = fold_ranges(&mut config.buffer, config.settings.fold_method);
+            if let Some(start) = hidden_by(config, config.cy) {
+                config.cy = start;
+                config.cx = 0;
+            }
+            Ok(())
+        },
+    },
+    Command {
+        name: "unfold-all",
+        arg: Arg::None,
+        help: "open every fold",
+        run: |config, _| {
+            config.folds.clear();
+            Ok(())
+        },
+    },
+    Command {
+        name: "toggle-whitespace",
+        arg: Arg::None,
+        help: "show or hide tabs and trailing spaces",
+        run: |config, _| {
+            config.settings.show_whitespace = !config.settings.show_whitespace;
+            Ok(())
+        },
+    },
+    Command {
+        name: "set",
+        arg: Arg::Setting,
+        help: "change a setting, e.g. `set tabstop=4`",
+        run: set,
+    },
+    Command {
+        name: "find",
+        arg: Arg::None,
+        help: "search the buffer",
+        run: |config, _| find(config),
+    },
+    Command {
+        name: "quit",
+        arg: Arg::None,
+        help: "quit, asking again if there are unsaved changes",
+        run: |config, _| quit(config),
+    },
+    Command {
+        name: "quit!",
+        arg: Arg::None,
+        help: "quit and discard unsaved changes",
+        run: |config, _| {
+            config.quit_times = 0;
+            quit(config)
+        },
+    },
+    Command {
+        name: "suspend",
+        arg: Arg::None,
+        help: "stop the editor and return to the shell until it is continued",
+        run: |config, _| suspend(config),
+    },
+    Command {
+        name: "write-quit",
+        arg: Arg::None,
+        help: "save and quit",
+        run: write_quit,
+    },
+    Command {
+        name: "help",
+        arg: Arg::Command,
+        help: "describe a command",
+        run: help,
+    },
+    Command {
+        name: "command-palette",
+        arg: Arg::None,
+        help: "run a command by name",
+        run: |config, _| command_palette(config),
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Splits a command line like `set tabstop=4` into its name and argument.
+fn split(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (line, ""),
+    }
+}
+
+/// Checks that a command line names a registered command, for validating key
+/// bindings before they are used.
+pub fn is_valid(line: &str) -> bool {
+    let (name, arg) = split(line);
+    match lookup(name) {
+        Some(cmd) => cmd.arg != Arg::None || arg.is_empty(),
+        None => false,
+    }
+}
+
+pub fn run_command(config: &mut EditorConfig, line: &str) -> Result<()> {
+    let (name, arg) = split(line);
+    let Some(cmd) = lookup(name) else {
+        return set_status_msg(config, format!("Unknown command: {name}"));
+    };
+    if !arg.is_empty() && cmd.arg == Arg::None {
+        return set_status_msg(config, format!("{name}: takes no argument"));
+    }
+    if arg.is_empty() && matches!(cmd.arg, Arg::Required | Arg::Setting | Arg::Command) {
+        return set_status_msg(config, format!("{name}: argument required"));
+    }
+    (cmd.run)(config, arg)
+}
+
+fn command_palette(config: &mut EditorConfig) -> Result<()> {
+    if let Some(line) = prompt(config, ":", None, Some(complete))? {
+        run_command(config, &line)?;
+    }
+    Ok(())
+}
+
+fn save_as(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    let name = if arg.is_empty() {
+        match prompt(
+            config,
+            "Save as (ESC to cancel):",
+            None,
+            Some(complete_path),
+        )? {
+            Some(name) => name,
+            None => return set_status_msg(config, "Save aborted".to_string()),
+        }
+    } else {
+        arg.to_string()
+    };
+    write_file(config, name)
+}
+
+fn sudo_save(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    let path = match (arg, &config.filename) {
+        ("", Some(file)) => file.clone(),
+        ("", None) => return set_status_msg(config, "sudo-save: no file name".to_string()),
+        (path, _) => path.to_string(),
+    };
+    crate::sudo_save(config, &path)
+}
+
+fn filter(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if refuse_edit(config)? {
+        return Ok(());
+    }
+    let selection = selection(config);
+    let (start, end) = selection.unwrap_or(((0, 0), (config.buffer.len(), 0)));
+    let input = config.buffer.text_between(start, end);
+    let output = match shell::run(arg, &input, || config.events.terminating()) {
+        Ok(output) => output,
+        Err(e) => return set_status_msg(config, e),
+    };
+    if output.stdout != input {
+        config.buffer.replace_between(start, end, &output.stdout);
+        config.folds.clear();
+        config.dirty = true;
+    }
+    config.mark = None;
+    // The whole buffer may have changed, so stay on the same row as a best
+    // guess of where the cursor's text went.
+    if selection.is_some() {
+        (config.cy, config.cx) = start;
+    }
+    config.cy = config.cy.min(config.buffer.len());
+    if config.cy < config.buffer.len() {
+        config.cx = config.cx.min(config.buffer.line_len(config.cy));
+    } else {
+        config.cx = 0;
+    }
+    let msg = output
+        .message
+        .unwrap_or_else(|| format!("Filtered through `{arg}`"));
+    set_status_msg(config, msg)
+}
+
+fn insert_output(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if refuse_edit(config)? {
+        return Ok(());
+    }
+    let output = match shell::run(arg, "", || config.events.terminating()) {
+        Ok(output) => output,
+        Err(e) => return set_status_msg(config, e),
+    };
+    let at = (config.cy, config.cx);
+    config.buffer.replace_between(at, at, &output.stdout);
+    config.mark = None;
+    config.folds.clear();
+    match output.stdout.rsplit_once('\n') {
+        Some((before, after)) => {
+            config.cy += before.matches('\n').count() + 1;
+            config.cx = after.chars().count();
+        }
+        None => config.cx += output.stdout.chars().count(),
+    }
+    config.dirty |= !output.stdout.is_empty();
+    let msg = output
+        .message
+        .unwrap_or_else(|| format!("Inserted the output of `{arg}`"));
+    set_status_msg(config, msg)
+}
+
+fn run_shell(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if !confirm_discard(config)? {
+        return Ok(());
+    }
+    let output = match shell::run(arg, "", || config.events.terminating()) {
+        Ok(output) => output,
+        Err(e) => return set_status_msg(config, e),
+    };
+    let buffer = Buffer::from_reader(output.stdout.as_bytes(), config.settings.tab_stop)?;
+    // Output shown over earlier output still goes back to the file.
+    let stash = match config.stash.take() {
+        Some(stash) => stash,
+        None => Stash {
+            buffer: mem::replace(&mut config.buffer, Buffer::new(config.settings.tab_stop)),
+            filename: config.filename.clone(),
+            mtime: config.mtime,
+            cursor: (config.cy, config.cx),
+            scroll: (config.row_off, config.wrap_off, config.col_off),
+            folds: mem::take(&mut config.folds),
+            read_only: config.read_only,
+            autosave: config.autosave,
+            backup: config.backup,
+            last_backup: config.last_backup,
+        },
+    };
+    set_buffer(config, buffer, None);
+    config.stash = Some(stash);
+    let msg = output
+        .message
+        .unwrap_or_else(|| format!("Output of `{arg}`; close-output goes back to the file"));
+    set_status_msg(config, msg)
+}
+
+fn close_output(config: &mut EditorConfig, _: &str) -> Result<()> {
+    let Some(stash) = config.stash.take() else {
+        return set_status_msg(config, "No output of `run` to close".to_string());
+    };
+    set_buffer(config, stash.buffer, stash.filename);
+    (config.cy, config.cx) = stash.cursor;
+    (config.row_off, config.wrap_off, config.col_off) = stash.scroll;
+    config.folds = stash.folds;
+    config.read_only = stash.read_only;
+    (config.autosave, config.backup) = (stash.autosave, stash.backup);
+    config.last_backup = stash.last_backup;
+    lsp_open(config)?;
+    // Reload the file if it changed while the output was shown.
+    let mtime = mem::replace(&mut config.mtime, stash.mtime);
+    match (config.path.clone(), mtime) {
+        (Some(path), Some(mtime)) => file_changed(config, path, mtime),
+        _ => Ok(()),
+    }
+}
+
+fn open_file(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if !confirm_discard(config)? {
+        return Ok(());
+    }
+    let name = if arg.is_empty() {
+        match prompt(config, "Open (ESC to cancel):", None, Some(complete_path))? {
+            Some(name) => name,
+            None => return Ok(()),
+        }
+    } else {
+        arg.to_string()
+    };
+    if let Err(e) = open(config, name) {
+        set_status_msg(config, format!("Can't open file: {e}"))?;
+    }
+    Ok(())
+}
+
+fn switch_file(config: &mut EditorConfig, step: isize) -> Result<()> {
+    let count = config.files.len();
+    if count < 2 {
+        return set_status_msg(config, "No other files to open".to_string());
+    }
+    if !confirm_discard(config)? {
+        return Ok(());
+    }
+    let idx = (config.file_idx as isize + step).rem_euclid(count as isize) as usize;
+    match open_arg(config, idx) {
+        Ok(()) => set_status_msg(config, format!("File {} of {count}", idx + 1)),
+        Err(e) => set_status_msg(config, format!("Can't open file: {e}")),
+    }
+}
+
+fn build(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if let Some((command, _)) = &config.build {
+        let msg = format!("`{command}` is still running");
+        return set_status_msg(config, msg);
+    }
+    let command = match arg {
+        "" => config.settings.build_command.clone(),
+        arg => arg.to_string(),
+    };
+    // Tools print paths relative to where they run, usually the project.
+    let dir = config
+        .path
+        .as_deref()
+        .map_or_else(|| PathBuf::from("."), project_root);
+    let tx = config.events.sender();
+    shell::spawn(&command, &dir, move |result| {
+        let _ = tx.send(events::Event::Build(result));
+    });
+    config.build = Some((command.clone(), dir));
+    set_status_msg(config, format!("Running `{command}`..."))
+}
+
+fn goto_diagnostic(config: &mut EditorConfig, step: isize) -> Result<()> {
+    let count = config.diagnostics.len();
+    if count == 0 {
+        return set_status_msg(config, "No diagnostics; run build first".to_string());
+    }
+    let idx = match config.diagnostic_idx {
+        Some(idx) => (idx as isize + step).rem_euclid(count as isize) as usize,
+        None if step > 0 => 0,
+        None => count - 1,
+    };
+    let (path, diagnostic) = config.diagnostics[idx].clone();
+    if config.path.as_ref() != Some(&path) {
+        if !confirm_discard(config)? {
+            return Ok(());
+        }
+        // The path as printed is relative to where the build ran.
+        if let Err(e) = open(config, path.to_string_lossy().into_owned()) {
+            return set_status_msg(config, format!("Can't open file: {e}"));
+        }
+    }
+    config.diagnostic_idx = Some(idx);
+    config.cy = diagnostic.row.min(config.buffer.len());
+    config.cx = if config.cy < config.buffer.len() {
+        diagnostic.col.min(config.buffer.line_len(config.cy))
+    } else {
+        0
+    };
+    config.diagnostic_row = Some(config.cy);
+    let msg = format!("({}/{count}) {}", idx + 1, diagnostic.message);
+    set_status_msg(config, msg)
+}
+
+fn hover(config: &mut EditorConfig, _: &str) -> Result<()> {
+    let Some(result) = lsp_request(config, "hover", |c, path, at| c.hover(path, at))? else {
+        return Ok(());
+    };
+    let msg = lsp::hover_text(&result).unwrap_or_else(|| "hover: nothing here".to_string());
+    set_status_msg(config, msg)
+}
+
+fn goto_definition(config: &mut EditorConfig, _: &str) -> Result<()> {
+    let request = |c: &mut lsp::Client, path: &Path, at| c.definition(path, at);
+    let Some(result) = lsp_request(config, "goto-definition", request)? else {
+        return Ok(());
+    };
+    pick_location(config, "Definitions", lsp::locations(&result))
+}
+
+fn find_references(config: &mut EditorConfig, _: &str) -> Result<()> {
+    let request = |c: &mut lsp::Client, path: &Path, at| c.references(path, at);
+    let Some(result) = lsp_request(config, "find-references", request)? else {
+        return Ok(());
+    };
+    pick_location(config, "References", lsp::locations(&result))
+}
+
+/// Jumps to the only location, or to the one picked if there are several.
+fn pick_location(
+    config: &mut EditorConfig,
+    title: &str,
+    locations: Vec<lsp::Location>,
+) -> Result<()> {
+    match locations.len() {
+        0 => return set_status_msg(config, format!("{title}: none found")),
+        1 => return goto_location(config, &locations[0]),
+        _ => {}
+    }
+    config.choices = locations.iter().map(|l| describe(config, l)).collect();
+    let p = format!("{title} ({}, Tab to cycle):", locations.len());
+    let Some(choice) = prompt(config, &p, None, Some(complete_choice))? else {
+        return Ok(());
+    };
+    match config.choices.iter().position(|c| *c == choice) {
+        Some(idx) => goto_location(config, &locations[idx]),
+        None => set_status_msg(config, format!("{title}: no such location")),
+    }
+}
+
+/// `file:line:col: text` of a location, as offered by `pick_location`.
+fn describe(config: &EditorConfig, location: &lsp::Location) -> String {
+    let line = location.position.line;
+    let path = fs::canonicalize(&location.path).unwrap_or(location.path.clone());
+    let text = if config.path.as_ref() == Some(&path) {
+        (line < config.buffer.len()).then(|| config.buffer.line(line).to_string())
+    } else {
+        fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| text.lines().nth(line).map(str::to_string))
+    }
+    .unwrap_or_default();
+    let col = lsp::char_col(text.chars(), location.position.character);
+    let file = std::env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .unwrap_or(path);
+    format!(
+        "{}:{}:{}: {}",
+        file.display(),
+        line + 1,
+        col + 1,
+        text.trim()
+    )
+}
+
+fn rename(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    if refuse_edit(config)? {
+        return Ok(());
+    }
+    let name = match arg {
+        "" => match prompt(config, "Rename to (ESC to cancel):", None, None)? {
+            Some(name) => name,
+            None => return Ok(()),
+        },
+        arg => arg.to_string(),
+    };
+    let request = |c: &mut lsp::Client, path: &Path, at| c.rename(path, at, &name);
+    let Some(result) = lsp_request(config, "rename", request)? else {
+        return Ok(());
+    };
+    let changes = lsp::workspace_edit(&result);
+    if changes.is_empty() {
+        return set_status_msg(config, "rename: nothing to rename".to_string());
+    }
+    let files = changes.len();
+    let mut failed = Vec::new();
+    for (path, edits) in changes {
+        if config.path.as_ref() == Some(&fs::canonicalize(&path).unwrap_or(path.clone())) {
+            let end = (config.buffer.len(), 0);
+            let text = config.buffer.text_between((0, 0), end);
+            config
+                .buffer
+                .replace_between((0, 0), end, &lsp::apply_edits(&text, &edits));
+            config.cy = config.cy.min(config.buffer.len());
+            if config.cy < config.buffer.len() {
+                config.cx = config.cx.min(config.buffer.line_len(config.cy));
+            }
+            config.mark = None;
+            config.folds.clear();
+            config.dirty = true;
+            continue;
+        }
+        // Other files aren't open, so they are edited on disk, each one
+        // regardless of whether the others could be.
+        let filename = path.to_string_lossy();
+        let written = match fs::read_to_string(&path) {
+            Ok(text) => {
+                let text = lsp::apply_edits(&text, &edits);
+                write_backed_up(config, &filename, config.backup, |mut file| {
+                    file.write_all(text.as_bytes()).map(|()| text.len())
+                })
+            }
+            Err(e) => Err(format!("I/O error: {e}")),
+        };
+        if let Err(e) = written {
+            failed.push(format!("{filename} ({e})"));
+        }
+    }
+    let msg = match failed.len() {
+        0 => format!("Renamed to `{name}` in {files} file(s)"),
+        n => format!(
+            "Renamed to `{name}` in {} file(s); couldn't write {}",
+            files - n,
+            failed.join(", ")
+        ),
+    };
+    set_status_msg(config, msg)
+}
+
+fn complete_word(config: &mut EditorConfig, _: &str) -> Result<()> {
+    if refuse_edit(config)? {
+        return Ok(());
+    }
+    if config.cy >= config.buffer.len() {
+        return set_status_msg(config, "complete: nothing to complete".to_string());
+    }
+    let Some(result) = lsp_request(config, "complete", |c, path, at| c.completion(path, at))?
+    else {
+        return Ok(());
+    };
+    let items = lsp::completions(&result);
+    config.choices = items.iter().map(|c| c.label.clone()).collect();
+    let choice = match items.len() {
+        0 => return set_status_msg(config, "complete: no completions".to_string()),
+        1 => items[0].label.clone(),
+        n => {
+            let p = format!("Complete ({n}, Tab to cycle):");
+            match prompt(config, &p, None, Some(complete_choice))? {
+                Some(choice) => choice,
+                None => return Ok(()),
+            }
+        }
+    };
+    let Some(item) = items.iter().find(|c| c.label == choice) else {
+        return set_status_msg(config, format!("complete: no completion `{choice}`"));
+    };
+    // The server completes the word the cursor is at the end of.
+    let mut start = config.cx;
+    while start > 0
+        && char_at(config, config.cy, start - 1).is_some_and(|c| c.is_alphanumeric() || c == '_')
+    {
+        start -= 1;
+    }
+    let (at, cx) = (config.cy, config.cx);
+    config
+        .buffer
+        .replace_between((at, start), (at, cx), &item.text);
+    config.cx = start + item.text.chars().count();
+    config.mark = None;
+    config.dirty = true;
+    Ok(())
+}
+
+fn goto(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    match arg.parse::<usize>() {
+        Ok(line) => {
+            config.cy = line.saturating_sub(1).min(config.buffer.len());
+            config.cx = 0;
+            Ok(())
+        }
+        Err(_) => set_status_msg(config, format!("goto: invalid line number `{arg}`")),
+    }
+}
+
+fn match_bracket(config: &mut EditorConfig, _: &str) -> Result<()> {
+    /// How far to look for the partner before giving up.
+    const MAX_ROWS: usize = 10_000;
+
+    if config.cy >= config.buffer.len() {
+        return Ok(());
+    }
+    let rx = config.buffer.cx_to_rx(config.cy, config.cx);
+    match config.buffer.matching_bracket(config.cy, rx, MAX_ROWS) {
+        Some((row, rx)) => {
+            config.cy = row;
+            config.cx = config.buffer.rx_to_cx(row, rx);
+            Ok(())
+        }
+        None => set_status_msg(config, "No matching bracket".to_string()),
+    }
+}
+
+fn fold_toggle(config: &mut EditorConfig, _: &str) -> Result<()> {
+    let cy = config.cy;
+    if fold_end(config, cy).is_some() {
+        config.folds.retain(|f| f.0 != cy);
+        return Ok(());
+    }
+    let innermost = fold_ranges(&mut config.buffer, config.settings.fold_method)
+        .into_iter()
+        .filter(|&(start, end)| start <= cy && cy <= end)
+        .max_by_key(|&(start, _)| start);
+    match innermost {
+        Some((start, end)) => {
+            config.folds.push((start, end));
+            config.cy = start;
+            config.cx = config.cx.min(config.buffer.line_len(start));
+            Ok(())
+        }
+        None => set_status_msg(config, "Nothing to fold here".to_string()),
+    }
+}
+
+fn set(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    let Some((key, value)) = arg.split_once('=') else {
+        return set_status_msg(config, format!("set: expected key=value, got `{arg}`"));
+    };
+    let key = match key.trim() {
+        "tabstop" => "tab_stop",
+        key => key,
+    };
+    let value = settings::parse_value(value.trim());
+    if let Err(e) = config.settings.set(key, &value) {
+        return set_status_msg(config, format!("set: {e}"));
+    }
+    match key {
+        "tab_stop" => {
+            config.buffer.set_tab_stop(config.settings.tab_stop);
+        }
+        "quit_times" => config.quit_times = config.settings.quit_times,
+        "backup" => config.backup = config.settings.backup,
+        "autosave" | "autosave_idle" => {
+            config.autosave = config.settings.autosave;
+            match config.settings.autosave_idle {
+                0 => config.events.cancel_timer(events::Timer::Autosave),
+                idle => config.events.set_timer(
+                    events::Timer::Autosave,
+                    Instant::now() + Duration::from_secs(idle),
+                ),
+            }
+        }
+        _ => {}
+    }
+    set_status_msg(config, format!("{key}={value}"))
+}
+
+fn help(config: &mut EditorConfig, arg: &str) -> Result<()> {
+    let msg = match lookup(arg) {
+        Some(cmd) => match config.settings.key_for(cmd.name) {
+            Some(key) => format!("{}: {} ({})", cmd.name, cmd.help, key),
+            None => format!("{}: {}", cmd.name, cmd.help),
+        },
+        None => format!("help: unknown command `{arg}`"),
+    };
+    set_status_msg(config, msg)
+}
+
+fn write_quit(config: &mut EditorConfig, _: &str) -> Result<()> {
+    save(config)?;
+    if !config.dirty {
+        quit(config)?;
+    }
+    Ok(())
+}
+
+// Completion
+
+/// Scores `candidate` as a fuzzy (subsequence) match for `pattern`, favouring
+/// matches at the start and runs of consecutive characters. Returns `None`
+/// when `pattern` is not a subsequence of `candidate`.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i32> {
+    let mut score = 0;
+    let mut chars = candidate.chars().enumerate();
+    let mut last: Option<usize> = None;
+    for p in pattern.chars() {
+        let p = p.to_ascii_lowercase();
+        let (i, _) = chars.find(|(_, c)| c.to_ascii_lowercase() == p)?;
+        score += match last {
+            None if i == 0 => 10,
+            Some(l) if i == l + 1 => 5,
+            _ => 1,
+        };
+        last = Some(i);
+    }
+    Some(score - candidate.len() as i32 / 4)
+}
+
+fn fuzzy_filter<'a>(pattern: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut matches: Vec<(i32, &str)> = candidates
+        .filter_map(|c| fuzzy_score(pattern, c).map(|s| (s, c)))
+        .collect();
+    matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    matches.into_iter().map(|(_, c)| c.to_string()).collect()
+}
+
+/// Completes a command prompt: command names first, then the argument
+/// according to the command's `Arg` kind.
+pub fn complete(config: &EditorConfig, buf: &str) -> Vec<String> {
+    let Some((name, arg)) = buf.split_once(' ') else {
+        return fuzzy_filter(buf, COMMANDS.iter().map(|c| c.name));
+    };
+    let candidates = match lookup(name).map(|c| c.arg) {
+        Some(Arg::Path) => complete_path_str(arg),
+        Some(Arg::Command) => fuzzy_filter(arg, COMMANDS.iter().map(|c| c.name)),
+        Some(Arg::Setting) => match arg.strip_prefix("theme=") {
+            Some(name) => {
+                let names = config
+                    .settings
+                    .themes
+                    .iter()
+                    .map(|t| t.name.as_str())
+                    .chain(theme::builtin_names().iter().copied());
+                fuzzy_filter(name, names)
+                    .into_iter()
+                    .map(|n| format!("theme={n}"))
+                    .collect()
+            }
+            None => fuzzy_filter(arg, settings::OPTIONS.iter().copied())
+                .into_iter()
+                .map(|s| s + "=")
+                .collect(),
+        },
+        _ => Vec::new(),
+    };
+    candidates
+        .into_iter()
+        .map(|c| format!("{name} {c}"))
+        .collect()
+}
+
+/// Completes a pick from `config.choices`, in their order until something is
+/// typed.
+fn complete_choice(config: &EditorConfig, buf: &str) -> Vec<String> {
+    if buf.is_empty() {
+        return config.choices.clone();
+    }
+    fuzzy_filter(buf, config.choices.iter().map(String::as_str))
+}
+
+pub fn complete_path(_: &EditorConfig, buf: &str) -> Vec<String> {
+    complete_path_str(buf)
+}
+
+fn complete_path_str(buf: &str) -> Vec<String> {
+    let (dir, base) = match buf.rfind('/') {
+        Some(i) => (&buf[..=i], &buf[i + 1..]),
+        None => ("", buf),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    let names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            if name.starts_with('.') && !base.starts_with('.') {
+                return None;
+            }
+            let is_dir = e.file_type().is_ok_and(|t| t.is_dir());
+            Some(if is_dir { name + "/" } else { name })
+        })
+        .collect();
+    fuzzy_filter(base, names.iter().map(|n| n.as_str()))
+        .into_iter()
+        .map(|n| Path::new(dir).join(n).to_string_lossy().into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use kilo_rs::diagnostics::{Diagnostic, Severity};
+
+    use super::*;
+
+    #[test]
+    fn scores_subsequences() {
+        assert_eq!(fuzzy_score("xyz", "save"), None);
+        assert_eq!(fuzzy_score("sa", "as"), None);
+        assert!(fuzzy_score("", "save").is_some());
+        assert_eq!(fuzzy_score("SAVE", "save"), fuzzy_score("save", "save"));
+        // Matching at the start and in runs scores higher than scattered.
+        assert!(fuzzy_score("sa", "save") > fuzzy_score("sa", "sudo-save-as"));
+        assert!(fuzzy_score("fold", "fold-all") > fuzzy_score("fold", "find-old"));
+        // Shorter candidates win ties.
+        assert!(fuzzy_score("save", "save") > fuzzy_score("save", "save-file-as"));
+    }
+
+    #[test]
+    fn ranks_candidates() {
+        let names = ["sudo-save", "save-as", "save", "set", "suspend"];
+        assert_eq!(
+            fuzzy_filter("save", names.into_iter()),
+            ["save", "save-as", "sudo-save"]
+        );
+        assert_eq!(fuzzy_filter("s", names.into_iter())[..2], ["set", "save"]);
+        // Equal scores are sorted by name.
+        assert_eq!(
+            fuzzy_filter("s", ["set", "sat"].into_iter()),
+            ["sat", "set"]
+        );
+        assert_eq!(
+            fuzzy_filter("sd", names.into_iter()),
+            ["suspend", "sudo-save"]
+        );
+    }
+
+    /// An editor on `text`, with the cursor at the start of row 1.
+    fn editing(text: &str) -> EditorConfig {
+        let mut config = EditorConfig::new(settings::Settings::default()).unwrap();
+        let buffer = Buffer::from_reader(text.as_bytes(), 8).unwrap();
+        set_buffer(&mut config, buffer, None);
+        config.cy = 1;
+        config
+    }
+
+    #[test]
+    fn filters_the_selection_or_the_whole_buffer() {
+        let mut config = editing("c\nb\na\n");
+        filter(&mut config, "sort").unwrap();
+        assert_eq!(config.buffer.to_string(), "a\nb\nc\n");
+        assert!(config.dirty);
+        assert_eq!(config.cy, 1);
+
+        let mut config = editing("c\nb\na\n");
+        config.mark = Some((3, 0));
+        filter(&mut config, "sort").unwrap();
+        assert_eq!(config.buffer.to_string(), "c\na\nb\n");
+        assert_eq!(config.status_msg, "Filtered through `sort`");
+    }
+
+    #[test]
+    fn failed_commands_leave_the_buffer_alone() {
+        let mut config = editing("b\na\n");
+        filter(&mut config, "echo oops >&2; false").unwrap();
+        assert_eq!(config.buffer.to_string(), "b\na\n");
+        assert!(!config.dirty);
+        assert_eq!(config.status_msg, "echo oops >&2; false: oops");
+
+        insert_output(&mut config, "exit 2").unwrap();
+        assert_eq!(config.buffer.to_string(), "b\na\n");
+        assert_eq!(config.status_msg, "exit 2: exit status: 2");
+
+        run_shell(&mut config, "exit 2").unwrap();
+        assert_eq!(config.buffer.to_string(), "b\na\n");
+        assert!(config.stash.is_none());
+    }
+
+    #[test]
+    fn inserts_output_at_the_cursor() {
+        let mut config = editing("ab\ncd\n");
+        config.cx = 1;
+        insert_output(&mut config, "printf 'x\\ny'; echo note >&2").unwrap();
+        assert_eq!(config.buffer.to_string(), "ab\ncx\nyd\n");
+        assert_eq!((config.cy, config.cx), (2, 1));
+        assert_eq!(config.status_msg, "printf 'x\\ny'; echo note >&2: note");
+    }
+
+    #[test]
+    fn runs_commands_over_the_file() {
+        let mut config = editing("one\ntwo\n");
+        config.cx = 2;
+        config.folds.push((0, 1));
+        run_shell(&mut config, "echo out").unwrap();
+        assert_eq!(config.buffer.to_string(), "out\n");
+        run_shell(&mut config, "echo more").unwrap();
+        assert_eq!(config.buffer.to_string(), "more\n");
+
+        close_output(&mut config, "").unwrap();
+        assert_eq!(config.buffer.to_string(), "one\ntwo\n");
+        assert_eq!((config.cy, config.cx), (1, 2));
+        assert_eq!(config.folds, [(0, 1)]);
+        assert!(config.stash.is_none());
+
+        close_output(&mut config, "").unwrap();
+        assert_eq!(config.status_msg, "No output of `run` to close");
+    }
+
+    #[test]
+    fn save_as_keeps_the_name_unless_written() {
+        let mut config = editing("fn f() {}\n");
+        config.filename = Some("kept.txt".to_string());
+        save_as(&mut config, "/nonexistent/kilo_rs/new.rs").unwrap();
+        assert_eq!(config.filename.as_deref(), Some("kept.txt"));
+        assert!(config.buffer.syntax().is_none());
+        assert!(config.status_msg.starts_with("Can't save! "));
+
+        let dir = std::env::temp_dir().join(format!("kilo_rs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saved_as.rs").to_string_lossy().into_owned();
+        save_as(&mut config, &path).unwrap();
+        assert_eq!(config.filename.as_deref(), Some(path.as_str()));
+        assert_eq!(config.buffer.syntax().map(|s| s.filetype), Some("rust"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn f() {}\n");
+    }
+
+    #[test]
+    fn opens_diagnostics_of_other_files() {
+        let dir = std::env::temp_dir().join(format!("kilo_rs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("diagnosed.rs");
+        fs::write(&path, "fn f() {}\n").unwrap();
+        let mut config = editing("");
+        let diagnostic = Diagnostic {
+            file: "src/diagnosed.rs".to_string(),
+            row: 0,
+            col: 3,
+            severity: Severity::Error,
+            message: "error: oops".to_string(),
+        };
+        config.diagnostics = vec![(path.canonicalize().unwrap(), diagnostic)];
+        goto_diagnostic(&mut config, 1).unwrap();
+        assert_eq!(config.path, Some(path.canonicalize().unwrap()));
+        assert_eq!((config.cy, config.cx), (0, 3));
+        assert_eq!(config.status_msg, "(1/1) error: oops");
+    }
+
+    #[test]
+    fn set_applies_to_the_buffer() {
+        let mut config = EditorConfig::new(settings::Settings::default()).unwrap();
+        set(&mut config, "autosave=true").unwrap();
+        set(&mut config, "backup = true").unwrap();
+        assert!(config.autosave && config.backup);
+        set(&mut config, "tabstop=2").unwrap();
+        assert_eq!(config.settings.tab_stop, 2);
+    }
+}
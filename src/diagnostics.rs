@@ -0,0 +1,91 @@
+//! Errors and warnings reported by compilers and linters, read from their
+//! output in the `file:line:col: message` form of `cargo build
+//! --message-format=short`, gcc and most other tools.
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    /// Path of the file as printed, usually relative to where the tool ran.
+    pub file: String,
+    /// Row and column in the file, counting from 0.
+    pub row: usize,
+    pub col: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Every diagnostic in `output`. Lines that don't look like one are skipped.
+pub fn parse(output: &str) -> Vec<Diagnostic> {
+    output.lines().filter_map(parse_line).collect()
+}
+
+/// Parses `file:line:col: message` or `file:line: message`.
+fn parse_line(line: &str) -> Option<Diagnostic> {
+    let (file, rest) = line.split_once(':')?;
+    if file.is_empty() || file.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (row, rest) = rest.split_once(':')?;
+    let row = row.parse::<usize>().ok()?.checked_sub(1)?;
+    let (col, message) = match rest.split_once(':').map(|(c, m)| (c.parse::<usize>(), m)) {
+        Some((Ok(col), message)) => (col.saturating_sub(1), message),
+        _ => (0, rest),
+    };
+    let message = message.trim();
+    let severity = if message.starts_with("error") {
+        Severity::Error
+    } else if message.starts_with("warning") {
+        Severity::Warning
+    } else {
+        Severity::Note
+    };
+    Some(Diagnostic {
+        file: file.to_string(),
+        row,
+        col,
+        severity,
+        message: message.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cargo_short_messages() {
+        let output = "   Compiling kilo_rs v0.1.0\n\
+            src/main.rs:12:5: error[E0425]: cannot find value `x` in this scope\n\
+            src/lib.rs:3:1: warning: unused import: `std::fs`\n\
+            error: could not compile `kilo_rs` (bin \"kilo_rs\") due to 1 previous error\n";
+        let diagnostics = parse(output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0],
+            Diagnostic {
+                file: "src/main.rs".to_string(),
+                row: 11,
+                col: 4,
+                severity: Severity::Error,
+                message: "error[E0425]: cannot find value `x` in this scope".to_string(),
+            }
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!((diagnostics[1].row, diagnostics[1].col), (2, 0));
+    }
+
+    #[test]
+    fn column_is_optional() {
+        let diagnostics = parse("Makefile:7: missing separator\n  --> src/main.rs:1:1\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].row, diagnostics[0].col), (6, 0));
+        assert_eq!(diagnostics[0].severity, Severity::Note);
+        assert_eq!(diagnostics[0].message, "missing separator");
+    }
+}
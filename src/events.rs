@@ -0,0 +1,293 @@
+//! Everything the editor waits for, merged into one channel: terminal input,
+//! signals, background builds, the language server and changes to the file
+//! on disk, plus timers.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crossterm::event;
+use signal_hook::{
+    consts::{SIGCONT, SIGHUP, SIGTERM},
+    iterator::Signals,
+};
+
+/// How long the input thread waits for a key before checking whether it
+/// should stop reading the terminal.
+const INPUT_POLL: Duration = Duration::from_millis(100);
+/// How often the watched file is checked for modification.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The watched file and its modification time when last checked.
+type Watched = Option<(PathBuf, Option<SystemTime>)>;
+
+pub enum Event {
+    Terminal(event::Event),
+    /// SIGTERM, SIGHUP or SIGCONT arrived.
+    Signal(i32),
+    /// The build finished, with what it printed and how it exited.
+    Build(Result<(String, ExitStatus), String>),
+    /// The language server sent something, to be picked up with
+    /// `lsp::Client::poll`.
+    Lsp,
+    /// The watched file was modified at the given time.
+    FileChanged(PathBuf, SystemTime),
+    Timer(Timer),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timer {
+    /// The message in the message bar is due to disappear.
+    Message,
+    /// Typing has paused long enough to send the language server the buffer.
+    LspSync,
+    /// There has been no input for `autosave_idle` seconds.
+    Autosave,
+}
+
+/// When each pending timer goes off. A timer is pending at most once.
+#[derive(Default)]
+struct Timers(Vec<(Instant, Timer)>);
+
+impl Timers {
+    fn set(&mut self, timer: Timer, at: Instant) {
+        self.cancel(timer);
+        self.0.push((at, timer));
+    }
+
+    fn cancel(&mut self, timer: Timer) {
+        self.0.retain(|&(_, t)| t != timer);
+    }
+
+    /// The timer that goes off first, and when. Of timers due at the same
+    /// time, the one set first.
+    fn first(&self) -> Option<(Instant, Timer)> {
+        self.0.iter().copied().min_by_key(|&(at, _)| at)
+    }
+}
+
+pub struct Events {
+    tx: Sender<Event>,
+    rx: Receiver<Event>,
+    timers: Timers,
+    /// Set while another program reads the terminal, e.g. sudo asking for a
+    /// password. The input thread holds the lock while it reads.
+    input_paused: Arc<AtomicBool>,
+    input_lock: Arc<Mutex<()>>,
+    watched: Arc<Mutex<Watched>>,
+    /// Set as soon as SIGTERM or SIGHUP arrives, for code that runs too long
+    /// to wait for its event.
+    terminating: Arc<AtomicBool>,
+}
+
+impl Events {
+    /// Starts the threads that read the terminal, catch signals and watch
+    /// the file.
+    pub fn new() -> io::Result<Events> {
+        let (tx, rx) = mpsc::channel();
+        let events = Events {
+            tx,
+            rx,
+            timers: Timers::default(),
+            input_paused: Arc::new(AtomicBool::new(false)),
+            input_lock: Arc::new(Mutex::new(())),
+            watched: Arc::new(Mutex::new(None)),
+            terminating: Arc::new(AtomicBool::new(false)),
+        };
+
+        let (tx, paused, lock) = (
+            events.sender(),
+            events.input_paused.clone(),
+            events.input_lock.clone(),
+        );
+        thread::spawn(move || loop {
+            let guard = lock.lock().unwrap();
+            if paused.load(Ordering::Relaxed) {
+                drop(guard);
+                thread::sleep(INPUT_POLL);
+                continue;
+            }
+            // A terminal that can't be read any more is gone, which SIGHUP
+            // reports.
+            let event = match event::poll(INPUT_POLL) {
+                Ok(true) => match event::read() {
+                    Ok(event) => event,
+                    Err(_) => return,
+                },
+                Ok(false) => continue,
+                Err(_) => return,
+            };
+            if tx.send(Event::Terminal(event)).is_err() {
+                return;
+            }
+        });
+
+        let mut signals = Signals::new([SIGTERM, SIGHUP, SIGCONT])?;
+        let (tx, terminating) = (events.sender(), events.terminating.clone());
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal != SIGCONT {
+                    terminating.store(true, Ordering::Relaxed);
+                }
+                if tx.send(Event::Signal(signal)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let (tx, watched) = (events.sender(), events.watched.clone());
+        thread::spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            let mut watched = watched.lock().unwrap();
+            let Some((path, last)) = watched.as_mut() else {
+                continue;
+            };
+            let Some(mtime) = modified(path) else {
+                continue;
+            };
+            if *last != Some(mtime) {
+                *last = Some(mtime);
+                if tx.send(Event::FileChanged(path.clone(), mtime)).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(events)
+    }
+
+    /// A sender for events from other threads.
+    pub fn sender(&self) -> Sender<Event> {
+        self.tx.clone()
+    }
+
+    /// Sets `timer` to go off at `at`, replacing an earlier setting of it.
+    pub fn set_timer(&mut self, timer: Timer, at: Instant) {
+        self.timers.set(timer, at);
+    }
+
+    pub fn cancel_timer(&mut self, timer: Timer) {
+        self.timers.cancel(timer);
+    }
+
+    /// Waits for the next event or timer, whichever comes first.
+    pub fn next(&mut self) -> Event {
+        let Some((at, timer)) = self.timers.first() else {
+            // `self.tx` keeps the channel open.
+            return self.rx.recv().unwrap();
+        };
+        match self
+            .rx
+            .recv_timeout(at.saturating_duration_since(Instant::now()))
+        {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                self.cancel_timer(timer);
+                Event::Timer(timer)
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+    }
+
+    /// Stops reading the terminal, returning once the input thread has let
+    /// go of it, so that another program can read it.
+    pub fn pause_input(&self) {
+        self.input_paused.store(true, Ordering::Relaxed);
+        drop(self.input_lock.lock().unwrap());
+    }
+
+    pub fn resume_input(&self) {
+        self.input_paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether SIGTERM or SIGHUP arrived, even if its event is still queued.
+    pub fn terminating(&self) -> bool {
+        self.terminating.load(Ordering::Relaxed)
+    }
+
+    /// Watches `path` for modification by other programs, replacing the file
+    /// watched before.
+    pub fn watch(&self, path: Option<PathBuf>) {
+        *self.watched.lock().unwrap() = path.map(|path| {
+            let mtime = modified(&path);
+            (path, mtime)
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timers_go_off_in_order() {
+        let now = Instant::now();
+        let mut timers = Timers::default();
+        assert_eq!(timers.first(), None);
+        timers.set(Timer::Autosave, now + Duration::from_secs(2));
+        timers.set(Timer::Message, now + Duration::from_secs(1));
+        timers.set(Timer::LspSync, now + Duration::from_secs(1));
+        assert_eq!(
+            timers.first(),
+            Some((now + Duration::from_secs(1), Timer::Message))
+        );
+        timers.cancel(Timer::Message);
+        assert_eq!(
+            timers.first(),
+            Some((now + Duration::from_secs(1), Timer::LspSync))
+        );
+        timers.cancel(Timer::LspSync);
+        assert_eq!(
+            timers.first(),
+            Some((now + Duration::from_secs(2), Timer::Autosave))
+        );
+    }
+
+    #[test]
+    fn setting_a_timer_again_replaces_it() {
+        let now = Instant::now();
+        let mut timers = Timers::default();
+        timers.set(Timer::Autosave, now + Duration::from_secs(1));
+        timers.set(Timer::Message, now + Duration::from_secs(2));
+        timers.set(Timer::Autosave, now + Duration::from_secs(3));
+        assert_eq!(
+            timers.first(),
+            Some((now + Duration::from_secs(2), Timer::Message))
+        );
+        timers.cancel(Timer::Message);
+        assert_eq!(
+            timers.first(),
+            Some((now + Duration::from_secs(3), Timer::Autosave))
+        );
+        timers.cancel(Timer::Autosave);
+        assert_eq!(timers.first(), None);
+    }
+
+    #[test]
+    fn timers_go_off_while_input_is_paused() {
+        let mut events = Events::new().unwrap();
+        events.pause_input();
+        events.set_timer(Timer::Message, Instant::now() + Duration::from_millis(20));
+        assert!(matches!(events.next(), Event::Timer(Timer::Message)));
+
+        // Other events still come in first, and a timer goes off only once.
+        events.set_timer(Timer::LspSync, Instant::now());
+        events.sender().send(Event::Lsp).unwrap();
+        assert!(matches!(events.next(), Event::Lsp));
+        assert!(matches!(events.next(), Event::Timer(Timer::LspSync)));
+        assert_eq!(events.timers.first(), None);
+        events.resume_input();
+    }
+}
@@ -0,0 +1,129 @@
+//! Finding the regions of a buffer that can be folded away.
+
+use crate::{buffer::Buffer, syntax::Highlight};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FoldMethod {
+    /// A row folds the rows after it that are indented further.
+    Indent,
+    /// A row folds everything up to the row that closes a bracket it opens.
+    Brackets,
+}
+
+/// Every foldable region as `(start, end)` rows, sorted by `start`. The start
+/// row stays visible and the rows after it up to and including `end` are
+/// hidden. Nested regions are included, at most one per start row.
+pub fn fold_ranges(buffer: &mut Buffer, method: FoldMethod) -> Vec<(usize, usize)> {
+    let mut ranges = match method {
+        FoldMethod::Indent => indent_ranges(buffer),
+        FoldMethod::Brackets => bracket_ranges(buffer),
+    };
+    // Keep the outermost region of each start row.
+    ranges.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    ranges.dedup_by_key(|r| r.0);
+    ranges
+}
+
+/// Render width of the leading whitespace of row `at`, or `None` if the row
+/// is blank.
+fn indent(buffer: &Buffer, at: usize) -> Option<usize> {
+    let mut width = 0;
+    for c in buffer.line(at).chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += buffer.tab_stop() - width % buffer.tab_stop(),
+            _ => return Some(width),
+        }
+    }
+    None
+}
+
+fn indent_ranges(buffer: &Buffer) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    // Rows that may still start a region, with their indent, innermost last.
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut last = 0;
+    for at in 0..buffer.len() {
+        let Some(width) = indent(buffer, at) else {
+            continue;
+        };
+        while let Some(&(w, start)) = open.last() {
+            if w < width {
+                break;
+            }
+            open.pop();
+            if last > start {
+                ranges.push((start, last));
+            }
+        }
+        open.push((width, at));
+        last = at;
+    }
+    for (_, start) in open {
+        if last > start {
+            ranges.push((start, last));
+        }
+    }
+    ranges
+}
+
+fn bracket_ranges(buffer: &mut Buffer) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    for at in 0..buffer.len() {
+        let row = buffer.row(at);
+        for (c, hl) in row.render.chars().zip(&row.hl) {
+            if matches!(hl, Highlight::String | Highlight::Comment) {
+                continue;
+            }
+            match c {
+                '(' | '[' | '{' => open.push(at),
+                ')' | ']' | '}' => {
+                    if let Some(start) = open.pop() {
+                        if start < at {
+                            ranges.push((start, at));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syntax::HLDB;
+
+    use super::*;
+
+    fn ranges(text: &str, method: FoldMethod) -> Vec<(usize, usize)> {
+        let mut buffer = Buffer::from_reader(text.as_bytes(), 4).unwrap();
+        buffer.set_syntax(Some(HLDB[0]));
+        fold_ranges(&mut buffer, method)
+    }
+
+    #[test]
+    fn indent_skips_blank_rows() {
+        let text = "a\n  b\n\n    c\n  d\n\ne\n";
+        assert_eq!(ranges(text, FoldMethod::Indent), [(0, 4), (1, 3)]);
+    }
+
+    #[test]
+    fn tabs_count_as_indent() {
+        assert_eq!(ranges("a\n\tb\n    c\n", FoldMethod::Indent), [(0, 2)]);
+    }
+
+    #[test]
+    fn brackets_ignore_strings_and_comments() {
+        let text = "fn f() {\n    let s = \"}\"; // {\n    g(\n    );\n}\n";
+        assert_eq!(ranges(text, FoldMethod::Brackets), [(0, 4), (2, 3)]);
+    }
+
+    #[test]
+    fn one_range_per_start_row() {
+        let text = "f(|| {\n    x\n});\n";
+        assert_eq!(ranges(text, FoldMethod::Brackets), [(0, 2)]);
+    }
+}
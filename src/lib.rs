@@ -0,0 +1,11 @@
+//! Text storage and syntax highlighting behind the `kilo_rs` editor. They
+//! live in a library so that the benchmarks can drive them without a
+//! terminal.
+
+pub mod buffer;
+pub mod diagnostics;
+pub mod fold;
+pub mod lsp;
+pub mod syntax;
+#[cfg(feature = "tree-sitter")]
+pub mod treesitter;
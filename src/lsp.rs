@@ -0,0 +1,693 @@
+//! A client for language servers such as rust-analyzer, speaking the
+//! Language Server Protocol over the server's stdin and stdout.
+//!
+//! Messages from the server are read on another thread and picked up with
+//! [`Client::poll`], so the editor never blocks on the server. Positions in
+//! the protocol count UTF-16 code units, see [`utf16_col`] and [`char_col`]
+//! for converting them to and from char columns.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{self, Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::diagnostics::{Diagnostic, Severity};
+
+/// Row and column of a position in a document, the column counting UTF-16
+/// code units.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Location {
+    pub path: PathBuf,
+    pub position: Position,
+}
+
+/// Replacement of the text between two positions.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TextEdit {
+    pub start: Position,
+    pub end: Position,
+    pub new_text: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Completion {
+    pub label: String,
+    /// Text to insert in place of the word before the cursor.
+    pub text: String,
+}
+
+/// Something the server sent that the editor has to act on.
+#[derive(Debug)]
+pub enum Message {
+    /// Answer to the request with this id: its result, or the server's error.
+    Response(u64, Result<Value, String>),
+    /// Every diagnostic of the file, replacing the ones sent before. Their
+    /// columns are still in UTF-16 code units.
+    Diagnostics(PathBuf, Vec<Diagnostic>),
+    /// Error or warning the server wants shown to the user.
+    Show(String),
+    /// The server went away, with the reason.
+    Exited(String),
+}
+
+/// A language server running as a child process.
+pub struct Client {
+    /// Command the server was started with.
+    pub command: String,
+    child: Child,
+    stdin: ChildStdin,
+    incoming: Receiver<Value>,
+    next_id: u64,
+    /// Messages held back until the server has answered `initialize`, before
+    /// which it can't take any others.
+    queued: Option<Vec<Value>>,
+    exited: bool,
+}
+
+/// Id of the `initialize` request, which the client answers itself.
+const INITIALIZE: u64 = 0;
+/// How long [`Client::shutdown`] waits for each step of the server's exit.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+
+impl Client {
+    /// Starts `command` with `sh -c` and begins initializing it for the
+    /// project in `root`. `wake` is called from another thread whenever
+    /// there is something to [`Client::poll`].
+    pub fn start(
+        command: &str,
+        root: &Path,
+        wake: impl Fn() + Send + 'static,
+    ) -> io::Result<Client> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if tx.send(message).is_err() {
+                    return;
+                }
+                wake();
+            }
+            // Let the exit be polled.
+            drop(tx);
+            wake();
+        });
+
+        let mut client = Client {
+            command: command.to_string(),
+            child,
+            stdin,
+            incoming,
+            next_id: INITIALIZE + 1,
+            queued: Some(Vec::new()),
+            exited: false,
+        };
+        let name = root.file_name().map(|n| n.to_string_lossy().into_owned());
+        client.write(&json!({
+            "jsonrpc": "2.0",
+            "id": INITIALIZE,
+            "method": "initialize",
+            "params": {
+                "processId": process::id(),
+                "rootUri": path_to_uri(root),
+                "workspaceFolders": [{ "uri": path_to_uri(root), "name": name }],
+                "capabilities": {
+                    "general": { "positionEncodings": ["utf-16"] },
+                    "textDocument": {
+                        "synchronization": { "didSave": true },
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "completion": { "completionItem": { "snippetSupport": false } },
+                        "definition": {},
+                        "references": {},
+                        "rename": {},
+                        "publishDiagnostics": {},
+                    },
+                },
+            },
+        }));
+        Ok(client)
+    }
+
+    /// Whether the server has answered `initialize`.
+    pub fn initialized(&self) -> bool {
+        self.queued.is_none()
+    }
+
+    /// Everything the server has sent since the last call. Requests from the
+    /// server are answered here.
+    pub fn poll(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(message) => messages.extend(self.handle(message)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !self.exited {
+                        self.exited = true;
+                        let reason = match self.exit_status() {
+                            Some(status) => format!("`{}` exited: {status}", self.command),
+                            None => format!("`{}` closed its output", self.command),
+                        };
+                        messages.push(Message::Exited(reason));
+                    }
+                    break;
+                }
+            }
+        }
+        messages
+    }
+
+    /// How the server exited after closing its output, which it usually
+    /// does right away.
+    fn exit_status(&mut self) -> Option<process::ExitStatus> {
+        for _ in 0..10 {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn handle(&mut self, message: Value) -> Option<Message> {
+        let method = message.get("method").and_then(Value::as_str);
+        match (method, message.get("id")) {
+            (Some(method), Some(id)) => {
+                // Nothing is configurable and no capability is registered
+                // dynamically, so every request gets an empty answer.
+                let result = match method {
+                    "workspace/configuration" => {
+                        let items = message["params"]["items"].as_array().map_or(0, Vec::len);
+                        Value::Array(vec![Value::Null; items])
+                    }
+                    _ => Value::Null,
+                };
+                self.write(&json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+                None
+            }
+            (Some("textDocument/publishDiagnostics"), None) => {
+                diagnostics(&message["params"]).map(|(path, d)| Message::Diagnostics(path, d))
+            }
+            (Some("window/showMessage"), None) => {
+                let params = &message["params"];
+                // Only errors (1) and warnings (2) are worth interrupting for.
+                let message = params["message"].as_str()?;
+                (params["type"].as_u64()? <= 2).then(|| Message::Show(message.to_string()))
+            }
+            (Some(_), None) => None,
+            (None, Some(id)) => {
+                let id = id.as_u64()?;
+                let result = match message.get("error") {
+                    Some(error) => Err(error["message"]
+                        .as_str()
+                        .unwrap_or("request failed")
+                        .to_string()),
+                    None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+                };
+                if id != INITIALIZE {
+                    return Some(Message::Response(id, result));
+                }
+                self.write(&json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }));
+                for message in self.queued.take().unwrap_or_default() {
+                    self.write(&message);
+                }
+                result
+                    .err()
+                    .map(|e| Message::Show(format!("`{}`: {e}", self.command)))
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Writes `message` to the server. A server that has gone away shows up
+    /// in [`Client::poll`], so failures are left to it.
+    fn write(&mut self, message: &Value) {
+        let _ = write_message(&mut self.stdin, message);
+    }
+
+    fn send(&mut self, message: Value) {
+        match &mut self.queued {
+            Some(queued) => queued.push(message),
+            None => self.write(&message),
+        }
+    }
+
+    /// Sends a request, returning the id its [`Message::Response`] will have.
+    pub fn request(&mut self, method: &str, params: Value) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        id
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    pub fn did_open(&mut self, path: &Path, language_id: &str, version: usize, text: &str) {
+        let document = json!({
+            "uri": path_to_uri(path),
+            "languageId": language_id,
+            "version": version,
+            "text": text,
+        });
+        self.notify("textDocument/didOpen", json!({ "textDocument": document }));
+    }
+
+    /// Sends the whole new text of the document.
+    pub fn did_change(&mut self, path: &Path, version: usize, text: &str) {
+        let document = json!({ "uri": path_to_uri(path), "version": version });
+        let params = json!({ "textDocument": document, "contentChanges": [{ "text": text }] });
+        self.notify("textDocument/didChange", params);
+    }
+
+    pub fn did_save(&mut self, path: &Path) {
+        let document = json!({ "uri": path_to_uri(path) });
+        self.notify("textDocument/didSave", json!({ "textDocument": document }));
+    }
+
+    pub fn did_close(&mut self, path: &Path) {
+        let document = json!({ "uri": path_to_uri(path) });
+        self.notify("textDocument/didClose", json!({ "textDocument": document }));
+    }
+
+    pub fn hover(&mut self, path: &Path, position: Position) -> u64 {
+        self.request("textDocument/hover", position_params(path, position))
+    }
+
+    pub fn definition(&mut self, path: &Path, position: Position) -> u64 {
+        self.request("textDocument/definition", position_params(path, position))
+    }
+
+    pub fn references(&mut self, path: &Path, position: Position) -> u64 {
+        let mut params = position_params(path, position);
+        params["context"] = json!({ "includeDeclaration": true });
+        self.request("textDocument/references", params)
+    }
+
+    pub fn rename(&mut self, path: &Path, position: Position, new_name: &str) -> u64 {
+        let mut params = position_params(path, position);
+        params["newName"] = json!(new_name);
+        self.request("textDocument/rename", params)
+    }
+
+    pub fn completion(&mut self, path: &Path, position: Position) -> u64 {
+        self.request("textDocument/completion", position_params(path, position))
+    }
+
+    /// Shuts the server down: asks it to, giving it half a second to answer,
+    /// tells it to exit and waits as long again for that, killing it if it
+    /// hasn't. Returns how it exited.
+    pub fn shutdown(mut self) -> Option<process::ExitStatus> {
+        let id = self.request("shutdown", Value::Null);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while let Ok(message) = self
+            .incoming
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if message.get("method").is_none() && message["id"].as_u64() == Some(id) {
+                break;
+            }
+            self.handle(message);
+        }
+        self.notify("exit", Value::Null);
+
+        let Client {
+            mut child, stdin, ..
+        } = self;
+        drop(stdin);
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = child.kill();
+        child.wait().ok()
+    }
+}
+
+fn position_params(path: &Path, position: Position) -> Value {
+    json!({
+        "textDocument": { "uri": path_to_uri(path) },
+        "position": { "line": position.line, "character": position.character },
+    })
+}
+
+fn position(value: &Value) -> Option<Position> {
+    Some(Position {
+        line: value["line"].as_u64()? as usize,
+        character: value["character"].as_u64()? as usize,
+    })
+}
+
+fn diagnostics(params: &Value) -> Option<(PathBuf, Vec<Diagnostic>)> {
+    let path = uri_to_path(params["uri"].as_str()?)?;
+    let file = path.to_string_lossy().into_owned();
+    let diagnostics = params["diagnostics"]
+        .as_array()?
+        .iter()
+        .filter_map(|d| {
+            let start = position(&d["range"]["start"])?;
+            let severity = match d["severity"].as_u64() {
+                Some(1) | None => Severity::Error,
+                Some(2) => Severity::Warning,
+                Some(_) => Severity::Note,
+            };
+            let message = d["message"].as_str()?.lines().next().unwrap_or_default();
+            let message = match d["source"].as_str() {
+                Some(source) => format!("{source}: {message}"),
+                None => message.to_string(),
+            };
+            Some(Diagnostic {
+                file: file.clone(),
+                row: start.line,
+                col: start.character,
+                severity,
+                message,
+            })
+        })
+        .collect();
+    Some((path, diagnostics))
+}
+
+/// Text of a hover result without markdown code fences, on one line.
+pub fn hover_text(result: &Value) -> Option<String> {
+    let text = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        value => value["value"].as_str().map(str::to_string),
+    };
+    let text = match &result["contents"] {
+        Value::Array(items) => items.iter().filter_map(text).collect::<Vec<_>>().join("\n"),
+        contents => text(contents)?,
+    };
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("```") && *line != "---")
+        .collect();
+    (!lines.is_empty()).then(|| lines.join("  "))
+}
+
+/// Locations of a definition or references result, which may be one
+/// location, a list of them or a list of location links.
+pub fn locations(result: &Value) -> Vec<Location> {
+    let location = |value: &Value| {
+        let uri = value.get("uri").or_else(|| value.get("targetUri"))?;
+        let range = value
+            .get("range")
+            .or_else(|| value.get("targetSelectionRange"))?;
+        Some(Location {
+            path: uri_to_path(uri.as_str()?)?,
+            position: position(&range["start"])?,
+        })
+    };
+    match result {
+        Value::Array(items) => items.iter().filter_map(location).collect(),
+        Value::Null => Vec::new(),
+        result => location(result).into_iter().collect(),
+    }
+}
+
+/// Items of a completion result, which may be a list of them or a
+/// `CompletionList`.
+pub fn completions(result: &Value) -> Vec<Completion> {
+    let items = match result {
+        Value::Array(items) => items,
+        result => match result["items"].as_array() {
+            Some(items) => items,
+            None => return Vec::new(),
+        },
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            let label = item["label"].as_str()?.to_string();
+            let text = item["textEdit"]["newText"]
+                .as_str()
+                .or_else(|| item["insertText"].as_str())
+                .unwrap_or(&label)
+                .to_string();
+            Some(Completion { label, text })
+        })
+        .collect()
+}
+
+/// Edits of a rename result, by file.
+pub fn workspace_edit(result: &Value) -> Vec<(PathBuf, Vec<TextEdit>)> {
+    let edits = |edits: &Value| -> Vec<TextEdit> {
+        let Some(edits) = edits.as_array() else {
+            return Vec::new();
+        };
+        edits
+            .iter()
+            .filter_map(|edit| {
+                Some(TextEdit {
+                    start: position(&edit["range"]["start"])?,
+                    end: position(&edit["range"]["end"])?,
+                    new_text: edit["newText"].as_str()?.to_string(),
+                })
+            })
+            .collect()
+    };
+    if let Some(changes) = result["documentChanges"].as_array() {
+        return changes
+            .iter()
+            .filter_map(|change| {
+                let path = uri_to_path(change["textDocument"]["uri"].as_str()?)?;
+                Some((path, edits(&change["edits"])))
+            })
+            .collect();
+    }
+    match result["changes"].as_object() {
+        Some(changes) => changes
+            .iter()
+            .filter_map(|(uri, e)| Some((uri_to_path(uri)?, edits(e))))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// `text` with `edits` made to it. Edits don't overlap, so they are made from
+/// the last one back to keep the positions of the others valid.
+pub fn apply_edits(text: &str, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<&TextEdit> = edits.iter().collect();
+    edits.sort_by_key(|edit| edit.start);
+    let mut text = text.to_string();
+    for edit in edits.into_iter().rev() {
+        let start = byte_offset(&text, edit.start);
+        let end = byte_offset(&text, edit.end).max(start);
+        text.replace_range(start..end, &edit.new_text);
+    }
+    text
+}
+
+/// Offset in bytes of `position` in `text`, clamped to the end of its line.
+fn byte_offset(text: &str, position: Position) -> usize {
+    let mut start = 0;
+    for _ in 0..position.line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let line = text[start..].split('\n').next().unwrap_or_default();
+    let cx = char_col(line.chars(), position.character);
+    start + line.chars().take(cx).map(char::len_utf8).sum::<usize>()
+}
+
+/// Column in UTF-16 code units of char column `cx` of a line.
+pub fn utf16_col(line: impl Iterator<Item = char>, cx: usize) -> usize {
+    line.take(cx).map(char::len_utf16).sum()
+}
+
+/// Char column of column `col` in UTF-16 code units of a line, clamped to the
+/// end of the line.
+pub fn char_col(line: impl Iterator<Item = char>, col: usize) -> usize {
+    let mut units = 0;
+    let mut cx = 0;
+    for c in line {
+        if c == '\n' || units >= col {
+            break;
+        }
+        units += c.len_utf16();
+        cx += 1;
+    }
+    cx
+}
+
+/// `file://` URI of an absolute path.
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for &b in path.to_string_lossy().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{b:02X}"));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        if path[i] == b'%' {
+            let hex = std::str::from_utf8(path.get(i + 1..i + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(path[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Reads one `Content-Length` framed message, or `None` at the end of input.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_utf16_columns() {
+        let line = "a😀b\n";
+        assert_eq!(utf16_col(line.chars(), 2), 3);
+        assert_eq!(char_col(line.chars(), 3), 2);
+        assert_eq!(char_col(line.chars(), 99), 3);
+        let path = Path::new("/tmp/a b/ü.rs");
+        assert_eq!(path_to_uri(path), "file:///tmp/a%20b/%C3%BC.rs");
+        assert_eq!(uri_to_path(&path_to_uri(path)).as_deref(), Some(path));
+    }
+
+    /// The mock server in `examples/mock_lsp.rs`, which `cargo test` builds
+    /// next to the test binaries.
+    fn mock_server() -> String {
+        let exe = std::env::current_exe().unwrap();
+        let path = exe
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("examples/mock_lsp");
+        assert!(path.exists(), "{} is missing", path.display());
+        path.to_string_lossy().into_owned()
+    }
+
+    fn wait(client: &mut Client) -> Message {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Some(message) = client.poll().into_iter().next() {
+                return message;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no message from the server");
+    }
+
+    fn response(client: &mut Client, id: u64) -> Value {
+        match wait(client) {
+            Message::Response(i, Ok(result)) if i == id => result,
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    #[test]
+    fn talks_to_a_server() {
+        let mut client = Client::start(&mock_server(), Path::new("/tmp"), || {}).unwrap();
+        let path = Path::new("/tmp/mock.rs");
+        let text = "let 😀 = bad;\n";
+        client.did_open(path, "rust", 0, text);
+        match wait(&mut client) {
+            Message::Diagnostics(p, diagnostics) => {
+                assert_eq!(p, path);
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!((diagnostics[0].row, diagnostics[0].col), (0, 9));
+                assert_eq!(diagnostics[0].severity, Severity::Warning);
+                assert_eq!(diagnostics[0].message, "mock: bad word");
+            }
+            message => panic!("unexpected {message:?}"),
+        }
+
+        let bad = Position {
+            line: 0,
+            character: utf16_col(text.chars(), 8),
+        };
+        let id = client.hover(path, bad);
+        assert_eq!(
+            hover_text(&response(&mut client, id)).as_deref(),
+            Some("fn mock()")
+        );
+        let id = client.references(path, bad);
+        let found = locations(&response(&mut client, id));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].position, bad);
+        let id = client.rename(path, bad, "good");
+        let edits = workspace_edit(&response(&mut client, id));
+        assert_eq!(edits[0].0, path);
+        assert_eq!(apply_edits(text, &edits[0].1), "let 😀 = good;\n");
+        let id = client.completion(path, bad);
+        let items = completions(&response(&mut client, id));
+        let texts: Vec<&str> = items.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["alpha", "beta()"]);
+
+        let status = client.shutdown();
+        assert!(status.is_some_and(|s| s.success()), "{status:?}");
+    }
+}
@@ -1,15 +1,17 @@
 use std::{
     fmt::Write,
-    fs::{File, OpenOptions},
-    io::{stdout, BufRead, BufReader, Stdout, Write as _},
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    fs::{self, File, OpenOptions},
+    io::{self, stdout, BufReader, Read, Stdout, Write as _},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{Error, Result};
 use crossterm::{
     cursor,
-    event::{read, Event, KeyCode, KeyModifiers},
+    event::{DisableFocusChange, EnableFocusChange, Event, KeyCode},
     execute, style,
     terminal::{
         disable_raw_mode, enable_raw_mode, size, Clear, ClearType, EnterAlternateScreen,
@@ -18,66 +20,47 @@ use crossterm::{
     QueueableCommand,
 };
 
+use kilo_rs::{
+    buffer::{Buffer, Indent},
+    diagnostics::{self, Diagnostic, Severity},
+    lsp,
+    syntax::{Highlight, Syntax, DEFAULT_PAIRS, HLDB},
+};
+use serde_json::Value;
+use settings::{LineNumbers, Settings};
+use signal_hook::consts::{SIGCONT, SIGTERM, SIGTSTP};
+
+mod cli;
+mod commands;
+mod events;
+mod settings;
+mod shell;
+mod statusline;
+mod theme;
+
 const KILO_RS_VERSION: &str = "0.1.1";
 const KILO_RS_TAB_STOP: usize = 8;
 const KILO_RS_QUIT_TIMES: u8 = 3;
-const HL_HIGHLIGHT_NUMBERS: u32 = 1 << 0;
-const HL_HIGHLIGHT_STRINGS: u32 = 1 << 1;
+/// How long after backing a file up saving it backs it up again.
+const KILO_RS_BACKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 type Callback = Box<dyn Fn(&mut EditorConfig, &str, KeyCode)>;
+type Completer = fn(&EditorConfig, &str) -> Vec<String>;
 
-#[derive(Clone, Copy, PartialEq)]
-enum Highlight {
-    Normal,
-    Number,
-    String,
-    Comment,
-    Keyword1,
-    Keyword2,
-}
-
-impl Highlight {
-    fn to_color(self) -> u8 {
-        match self {
-            Self::Number => 31,
-            Self::String => 35,
-            Self::Comment => 36,
-            Self::Keyword1 => 33,
-            Self::Keyword2 => 32,
-            _ => 37,
-        }
-    }
-}
-
-#[derive(Clone, Copy)]
-struct Syntax {
-    filetype: &'static str,
-    filematch: &'static [&'static str],
-    keywords: &'static [&'static str],
-    single_line_comment_start: Option<&'static str>,
-    flags: u32,
-}
-
-const HLDB: [Syntax; 1] = [Syntax {
-    filetype: "rust",
-    filematch: &["rs"],
-    keywords: &[
-        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
-        "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
-        "pub", "ref", "return", "self", "static", "struct", "trait", "type", "unsafe", "use",
-        "Vec", "Option", "vec", "where", "while", "i8|", "i16|", "i32|", "i64|", "i128|", "isize|",
-        "u8|", "u16|", "u32|", "u64|", "u128|", "usize|", "f32|", "f64|", "char|", "bool|",
-        "&str|", "str|", "()|", "String|",
-    ],
-    single_line_comment_start: Some("//"),
-    flags: HL_HIGHLIGHT_NUMBERS | HL_HIGHLIGHT_STRINGS,
-}];
-
-struct Row {
-    content: String,
-    render: String,
-    rsize: usize,
-    hl: Vec<Highlight>,
+/// A file put aside while `run` shows its output, with everything needed to
+/// go back to where the user was in it.
+struct Stash {
+    buffer: Buffer,
+    filename: Option<String>,
+    mtime: Option<SystemTime>,
+    cursor: (usize, usize),
+    /// `row_off`, `wrap_off` and `col_off`.
+    scroll: (usize, usize, usize),
+    folds: Vec<(usize, usize)>,
+    read_only: bool,
+    autosave: bool,
+    backup: bool,
+    last_backup: Option<Instant>,
 }
 
 struct EditorConfig {
@@ -89,385 +72,879 @@ struct EditorConfig {
     rx: usize,
     col_off: usize,
     row_off: usize,
-    row: Vec<Row>,
+    /// First screen line of `row_off` shown when soft wrapping.
+    wrap_off: usize,
+    buffer: Buffer,
     filename: Option<String>,
+    /// The file the buffer shows the output of `run` over, if it does.
+    stash: Option<Stash>,
     status_msg: String,
-    status_msg_time: u64,
+    /// When the message disappears, or `None` if it stays until replaced.
+    status_msg_expiry: Option<Instant>,
     dirty: bool,
+    /// Row and render columns of the match shown while searching.
+    search_match: Option<(usize, usize, usize)>,
+    /// Row and render column of the bracket matching the one under the
+    /// cursor.
+    bracket_match: Option<(usize, usize)>,
+    /// Row and column where the selection started; the cursor is its other
+    /// end.
+    mark: Option<(usize, usize)>,
+    /// Closed folds as `(start, end)` rows. The start row is drawn with a
+    /// summary and the rows after it up to `end` are hidden.
+    folds: Vec<(usize, usize)>,
+    /// Indentation of the file as of the last open or save.
+    indent: Indent,
+    /// Whether edits to the buffer are refused.
+    read_only: bool,
+    /// Whether every file is opened read-only (`-R`).
+    view: bool,
+    /// File type forced with `--syntax`, used instead of guessing one from
+    /// the file name.
     syntax: Option<Syntax>,
+    /// Files given on the command line, and which of them is open.
+    files: Vec<cli::FileArg>,
+    file_idx: usize,
+    /// Standard input, read at startup if it was given as the file `-`.
+    stdin: Option<String>,
+    events: events::Events,
+    /// Command of the build started with `build` and the directory it runs
+    /// in, while it is running.
+    build: Option<(String, PathBuf)>,
+    /// Diagnostics of the last build, each with the canonical path of its
+    /// file.
+    diagnostics: Vec<(PathBuf, Diagnostic)>,
+    /// Diagnostic last jumped to with `next-error` or `prev-error`.
+    diagnostic_idx: Option<usize>,
+    /// Row the cursor was on when the message bar was last given the
+    /// diagnostic under it.
+    diagnostic_row: Option<usize>,
+    /// Canonical path of the file, to match diagnostics against.
+    path: Option<PathBuf>,
+    /// Modification time of the file as of the last open or save.
+    mtime: Option<SystemTime>,
+    /// Whether another program changed the file while the buffer had
+    /// changes, which autosave mustn't overwrite. Cleared by opening or
+    /// saving the file.
+    changed_on_disk: bool,
+    /// Whether the buffer is saved by itself once idle or unfocused.
+    autosave: bool,
+    /// Whether the file is backed up before saving overwrites it, and when
+    /// it last was.
+    backup: bool,
+    last_backup: Option<Instant>,
+    /// Language server for the file's type, if one is configured.
+    lsp: Option<lsp::Client>,
+    /// Language server commands that failed to start, which aren't tried
+    /// again.
+    lsp_failed: Vec<String>,
+    /// Files that weren't open when the language server sent their
+    /// diagnostics, whose columns still count UTF-16 units.
+    lsp_utf16: Vec<PathBuf>,
+    /// Version of the buffer the language server last got, or `None` if the
+    /// file isn't open in it.
+    lsp_version: Option<usize>,
+    /// Last response from the language server, picked up by `lsp_request`.
+    lsp_response: Option<(u64, Result<Value, String>)>,
+    /// Candidates offered while picking a reference or completion.
+    choices: Vec<String>,
+    /// Branch of the git repository the file is in, as of the last open or
+    /// save.
+    git_branch: Option<String>,
+    settings: Settings,
+    quit_times: u8,
+    /// Whether `suspend` stopped the editor and set the terminal up again
+    /// itself, so the SIGCONT that continued it needs nothing more.
+    suspended: bool,
 }
 
 impl EditorConfig {
-    fn new() -> Result<Self> {
-        let (screen_cols, screen_rows) = size()?;
+    /// An editor with an empty buffer and a text area of no size, which
+    /// `update_size` fits to the terminal.
+    fn new(settings: Settings) -> Result<Self> {
         Ok(EditorConfig {
             stdout: stdout(),
-            screen_rows: (screen_rows - 2) as usize,
-            screen_cols: screen_cols as usize,
+            screen_rows: 0,
+            screen_cols: 0,
             cx: 0,
             cy: 0,
             rx: 0,
             col_off: 0,
             row_off: 0,
-            row: Vec::new(),
+            wrap_off: 0,
+            buffer: Buffer::new(settings.tab_stop),
             filename: None,
+            stash: None,
             status_msg: String::new(),
-            status_msg_time: 0,
+            status_msg_expiry: None,
             dirty: false,
+            search_match: None,
+            bracket_match: None,
+            mark: None,
+            folds: Vec::new(),
+            indent: Indent::None,
+            read_only: false,
+            view: false,
             syntax: None,
+            files: Vec::new(),
+            file_idx: 0,
+            stdin: None,
+            events: events::Events::new()?,
+            build: None,
+            diagnostics: Vec::new(),
+            diagnostic_idx: None,
+            diagnostic_row: None,
+            path: None,
+            mtime: None,
+            changed_on_disk: false,
+            autosave: settings.autosave,
+            backup: settings.backup,
+            last_backup: None,
+            lsp: None,
+            lsp_failed: Vec::new(),
+            lsp_utf16: Vec::new(),
+            lsp_version: None,
+            lsp_response: None,
+            choices: Vec::new(),
+            git_branch: None,
+            quit_times: settings.quit_times,
+            settings,
+            suspended: false,
         })
     }
 }
 
 // Terminal
 
-fn die(err: Error) -> ! {
-    disable_raw_mode().unwrap();
+fn enter_terminal() -> Result<()> {
+    execute!(
+        stdout(),
+        EnterAlternateScreen,
+        EnableFocusChange,
+        cursor::SetCursorStyle::SteadyBlock
+    )?;
+    enable_raw_mode()?;
+    Ok(())
+}
+
+/// Puts the terminal back the way the shell left it.
+fn leave_terminal() -> Result<()> {
+    disable_raw_mode()?;
     execute!(
         stdout(),
+        DisableFocusChange,
         LeaveAlternateScreen,
         cursor::SetCursorStyle::DefaultUserShape
-    )
-    .unwrap();
-    eprintln!("{err}");
-    std::process::exit(1);
+    )?;
+    Ok(())
 }
 
-// Syntax highlighting
-
-fn is_separator(c: char) -> bool {
-    c.is_whitespace() || c == '\0' || ",.()+-/*=~%<>[];".contains(c)
+/// Leaves the editor after an error it can't go on from, keeping a copy of
+/// unsaved changes.
+fn die(config: &EditorConfig, err: Error) -> ! {
+    let _ = leave_terminal();
+    eprintln!("{err}");
+    emergency_exit(config, 1);
 }
 
-fn update_syntax(syntax: Option<Syntax>, row: &mut Row) {
-    row.hl.resize(row.rsize, Highlight::Normal);
-
-    if syntax.is_none() {
-        return;
+/// Leaves the editor after a panic or a termination signal, keeping a copy of
+/// unsaved changes.
+fn emergency_exit(config: &EditorConfig, code: i32) -> ! {
+    let _ = leave_terminal();
+    if config.dirty {
+        match emergency_save(config) {
+            Ok(path) => eprintln!("kilo_rs: unsaved changes written to {path}"),
+            Err(e) => eprintln!("kilo_rs: can't write unsaved changes: {e}"),
+        }
     }
+    std::process::exit(code);
+}
 
-    let mut prev_sep = true;
-    let mut in_string = false;
-    let flags = syntax.unwrap().flags;
-
-    let scs = syntax.unwrap().single_line_comment_start;
-    let keywords = syntax.unwrap().keywords;
-
-    let mut i = 0;
-    while i < row.rsize {
-        let c = row.render.chars().nth(i).unwrap();
-        let prev_hl = if i > 0 {
-            row.hl[i - 1]
-        } else {
-            Highlight::Normal
-        };
-
-        if scs.is_some() && !in_string {
-            let s = scs.unwrap();
-            if row.render[i..].starts_with(s) {
-                for j in i..row.rsize {
-                    row.hl[j] = Highlight::Comment;
-                }
-                break;
-            }
-        }
+/// Sizes the text area to the terminal, leaving two rows for the status and
+/// message bars.
+fn update_size(config: &mut EditorConfig) -> Result<()> {
+    let (screen_cols, screen_rows) = size()?;
+    config.screen_rows = (screen_rows as usize).saturating_sub(2);
+    config.screen_cols = screen_cols as usize;
+    Ok(())
+}
 
-        if flags & HL_HIGHLIGHT_STRINGS != 0 {
-            if in_string {
-                row.hl[i] = Highlight::String;
+/// Hands the terminal back to the shell and stops, like Ctrl-Z does in other
+/// programs. Returns once the editor is continued, with the screen set up
+/// again.
+fn suspend(config: &mut EditorConfig) -> Result<()> {
+    leave_terminal()?;
+    config.suspended = true;
+    signal_hook::low_level::raise(SIGTSTP)?;
+    resume(config)
+}
 
-                if c == '\\' && i + 1 < row.rsize {
-                    row.hl[i + 1] = Highlight::String;
-                    i += 2;
-                    continue;
-                }
+/// Sets the terminal up again after the editor was stopped, since the shell
+/// and other programs may have changed it and its size in the meantime.
+fn resume(config: &mut EditorConfig) -> Result<()> {
+    enter_terminal()?;
+    update_size(config)
+}
 
-                if c == '"' {
-                    in_string = false;
-                }
-                i += 1;
-                prev_sep = true;
-                continue;
-            } else {
-                if c == '"' {
-                    in_string = true;
-                    row.hl[i] = Highlight::String;
-                    i += 1;
-                    continue;
-                }
+/// Waits for the next event. Terminal events other than resizes are returned
+/// for the caller to handle; everything else is handled here and the screen
+/// redrawn. A SIGTERM or SIGHUP ends the editor.
+fn next_event(config: &mut EditorConfig) -> Result<Option<Event>> {
+    match config.events.next() {
+        events::Event::Terminal(Event::Resize(..)) => update_size(config)?,
+        events::Event::Terminal(Event::FocusLost) => autosave(config)?,
+        events::Event::Terminal(Event::FocusGained) => {}
+        events::Event::Terminal(event) => {
+            // Catch the language server up once typing pauses, and save once
+            // the user has been idle for long enough.
+            let now = Instant::now();
+            config
+                .events
+                .set_timer(events::Timer::LspSync, now + Duration::from_millis(200));
+            if config.settings.autosave_idle > 0 {
+                let idle = Duration::from_secs(config.settings.autosave_idle);
+                config.events.set_timer(events::Timer::Autosave, now + idle);
             }
+            return Ok(Some(event));
         }
-
-        if flags & HL_HIGHLIGHT_NUMBERS != 0 {
-            if (c.is_ascii_digit() && (prev_sep || prev_hl == Highlight::Number))
-                || (c == '.' && prev_hl == Highlight::Number)
-            {
-                row.hl[i] = Highlight::Number;
-                i += 1;
-                prev_sep = false;
-                continue;
+        events::Event::Signal(SIGCONT) => {
+            // Only a stop from outside, e.g. `kill -STOP`, leaves the
+            // terminal to be set up again.
+            if !std::mem::take(&mut config.suspended) {
+                resume(config)?;
             }
         }
+        events::Event::Signal(signal) => emergency_exit(config, 128 + signal),
+        events::Event::Build(result) => build_finished(config, result)?,
+        events::Event::Lsp => poll_lsp(config)?,
+        events::Event::FileChanged(path, mtime) => file_changed(config, path, mtime)?,
+        // The expired message is left out of the redraw.
+        events::Event::Timer(events::Timer::Message) => {}
+        events::Event::Timer(events::Timer::LspSync) => lsp_sync(config),
+        events::Event::Timer(events::Timer::Autosave) => autosave(config)?,
+    }
+    refresh_screen(config)?;
+    Ok(None)
+}
 
-        if prev_sep {
-            let mut is_break = false;
-            for kw in keywords {
-                let kw2 = kw.ends_with('|');
-                let (kw, klen) = if kw2 {
-                    (&kw.trim_end_matches("|"), kw.len() - 1)
-                } else {
-                    (kw, kw.len())
-                };
-
-                if row.render[i..].starts_with(kw)
-                    && is_separator(row.render.chars().nth(i + klen).unwrap())
-                {
-                    let hl = if kw2 {
-                        Highlight::Keyword2
-                    } else {
-                        Highlight::Keyword1
-                    };
-                    for j in i..i + klen {
-                        row.hl[j] = hl;
-                    }
-                    i += klen;
-                    is_break = true;
-                    break;
-                }
-            }
-            if is_break {
-                prev_sep = false;
-                continue;
-            }
+/// Waits for the next event from the terminal, handling everything else that
+/// happens in the meantime.
+fn read_event(config: &mut EditorConfig) -> Result<Event> {
+    loop {
+        if let Some(event) = next_event(config)? {
+            return Ok(event);
         }
-
-        prev_sep = is_separator(c);
-        i += 1;
     }
 }
 
+// Syntax highlighting
+
 fn select_syntax_highlight(config: &mut EditorConfig) {
-    if config.filename.is_none() {
-        return;
+    if let Some(syntax) = config
+        .filename
+        .as_deref()
+        .and_then(|f| syntax_for(config, f))
+    {
+        config.buffer.set_syntax(Some(syntax));
     }
+}
 
-    if let Some(ext) = Path::new(config.filename.as_ref().unwrap().as_str()).extension() {
-        let ext = ext.to_str().unwrap();
-        for s in HLDB {
-            for fm in s.filematch {
-                if &ext == fm {
-                    config.syntax = Some(s);
-                    for row in config.row.iter_mut() {
-                        update_syntax(config.syntax, row);
-                    }
-                    return;
-                }
-            }
-        }
+/// File type of `filename`, unless one was forced with `--syntax`.
+fn syntax_for(config: &EditorConfig, filename: &str) -> Option<Syntax> {
+    if config.syntax.is_some() {
+        return config.syntax;
     }
+    let path = Path::new(filename);
+    let ext = path.extension().and_then(|e| e.to_str());
+    let name = path.file_name().and_then(|n| n.to_str());
+    HLDB.into_iter().find(|s| {
+        s.filematch
+            .iter()
+            .any(|&fm| Some(fm) == ext || Some(fm) == name)
+    })
 }
 
-// Row operations
+// editor operations
 
-fn row_cx_to_rx(row: &Row, cx: usize) -> usize {
-    let mut rx = 0;
-    for c in row.content.chars().take(cx) {
-        if c == '\t' {
-            rx += (KILO_RS_TAB_STOP - 1) - (rx % KILO_RS_TAB_STOP);
-        }
-        rx += 1;
+/// Start and end of the selection, in order, or `None` if nothing is
+/// selected.
+fn selection(config: &EditorConfig) -> Option<((usize, usize), (usize, usize))> {
+    let mark = config.mark?;
+    let cursor = (config.cy, config.cx);
+    match mark.cmp(&cursor) {
+        std::cmp::Ordering::Less => Some((mark, cursor)),
+        std::cmp::Ordering::Greater => Some((cursor, mark)),
+        std::cmp::Ordering::Equal => None,
     }
-    rx
 }
 
-fn row_rx_to_cx(row: &Row, rx: usize) -> usize {
-    let mut cur_rx = 0;
-    let mut ret_cx = 0;
-    for (cx, c) in row.content.chars().enumerate() {
-        if c == '\t' {
-            cur_rx += (KILO_RS_TAB_STOP - 1) - (cur_rx % KILO_RS_TAB_STOP);
-        }
-        ret_cx = cx;
-        cur_rx += 1;
-        if cur_rx > rx {
-            return cx;
-        }
+fn pairs(config: &EditorConfig) -> &'static [(char, char)] {
+    match config.buffer.syntax() {
+        Some(syntax) => syntax.pairs,
+        None => DEFAULT_PAIRS,
     }
-    ret_cx
 }
 
-fn update_row(syntax: Option<Syntax>, row: &mut Row) {
-    row.render.clear();
-    let mut idx = 0;
-    for c in row.content.chars() {
-        if c == '\t' {
-            row.render.push(' ');
-            idx += 1;
-            while idx % KILO_RS_TAB_STOP != 0 {
-                row.render.push(' ');
-                idx += 1;
-            }
-        } else {
-            row.render.push(c);
-            idx += 1;
-        }
+/// Char at column `cx` of row `at`, if there is one.
+fn char_at(config: &EditorConfig, at: usize, cx: usize) -> Option<char> {
+    if at >= config.buffer.len() {
+        return None;
     }
-    row.rsize = idx;
-    update_syntax(syntax, row);
+    config.buffer.line(at).get_char(cx)
 }
 
-fn insert_row(config: &mut EditorConfig, at: usize, s: &str) {
-    if at > config.row.len() {
-        return;
+/// Handles `c` specially when `auto_pairs` is on: wraps the selection in a
+/// pair, types over a closer that is already there, or inserts an opener
+/// together with its closer. Returns whether it did any of these.
+fn insert_pair(config: &mut EditorConfig, c: char) -> bool {
+    let pairs = pairs(config);
+    let pair = pairs.iter().find(|&&(open, _)| open == c).copied();
+
+    if let (Some((open, close)), Some((start, mut end))) = (pair, selection(config)) {
+        // A selection up to the empty row past the end closes on the last row.
+        if end.0 == config.buffer.len() {
+            end = (end.0 - 1, config.buffer.line_len(end.0 - 1));
+        }
+        config.buffer.insert_char(end.0, end.1, close);
+        config.buffer.insert_char(start.0, start.1, open);
+        config.cy = end.0;
+        config.cx = if start.0 == end.0 {
+            end.1 + 2
+        } else {
+            end.1 + 1
+        };
+        config.mark = None;
+        config.dirty = true;
+        return true;
     }
-    let row = Row {
-        content: s.to_string(),
-        render: String::new(),
-        rsize: 0,
-        hl: Vec::new(),
-    };
-    config.row.insert(at, row);
-    update_row(config.syntax, &mut config.row[at]);
-    config.dirty = true;
-}
 
-fn del_row(config: &mut EditorConfig, at: usize) {
-    if at >= config.row.len() {
-        return;
+    let next = char_at(config, config.cy, config.cx);
+    if next == Some(c) && pairs.iter().any(|&(_, close)| close == c) {
+        config.cx += 1;
+        return true;
     }
-    config.row.remove(at);
-    config.dirty = true;
-}
 
-fn row_insert_char(syntax: Option<Syntax>, row: &mut Row, at: usize, c: char) {
-    let at = if at > row.content.len() {
-        row.content.len()
-    } else {
-        at
+    let Some((open, close)) = pair else {
+        return false;
     };
-    row.content.insert(at, c);
-    update_row(syntax, row);
+    // Only pair up when the closer wouldn't end up glued to a word, and
+    // never for a quote typed right after one (as in `don't`).
+    let prev = config
+        .cx
+        .checked_sub(1)
+        .and_then(|cx| char_at(config, config.cy, cx));
+    let before_word = next.is_some_and(|n| n.is_alphanumeric() || n == '_');
+    let after_word = open == close && prev.is_some_and(|p| p.is_alphanumeric() || p == '_');
+    if before_word || after_word {
+        return false;
+    }
+    if config.cy == config.buffer.len() {
+        config.buffer.insert_row(config.buffer.len(), "");
+    }
+    config.buffer.insert_char(config.cy, config.cx, close);
+    config.buffer.insert_char(config.cy, config.cx, open);
+    config.cx += 1;
+    config.dirty = true;
+    true
 }
 
-fn row_append_string(syntax: Option<Syntax>, row: &mut Row, s: &str) {
-    row.content.push_str(s);
-    update_row(syntax, row);
+/// Whether the buffer is read-only, in which case the user is told why their
+/// edit did nothing.
+fn refuse_edit(config: &mut EditorConfig) -> Result<bool> {
+    if config.read_only {
+        set_status_msg(
+            config,
+            "Buffer is read-only; run toggle-read-only to edit it".to_string(),
+        )?;
+    }
+    Ok(config.read_only)
 }
 
-fn row_del_char(syntax: Option<Syntax>, row: &mut Row, at: usize) {
-    if at >= row.content.len() {
-        return;
+/// Whether the buffer can be replaced by another file without losing
+/// changes, which the user is otherwise told to save first.
+fn confirm_discard(config: &mut EditorConfig) -> Result<bool> {
+    if config.dirty {
+        set_status_msg(
+            config,
+            "Unsaved changes! Save first or use quit! to discard them".to_string(),
+        )?;
     }
-    row.content.remove(at);
-    update_row(syntax, row);
+    Ok(!config.dirty)
 }
 
-// editor operations
-
-fn insert_char(config: &mut EditorConfig, c: char) {
-    if config.cy == config.row.len() {
-        insert_row(config, config.row.len(), "");
+fn insert_char(config: &mut EditorConfig, c: char) -> Result<()> {
+    if refuse_edit(config)? || config.settings.auto_pairs && insert_pair(config, c) {
+        return Ok(());
+    }
+    config.mark = None;
+    if config.cy == config.buffer.len() {
+        config.buffer.insert_row(config.buffer.len(), "");
     }
-    row_insert_char(config.syntax, &mut config.row[config.cy], config.cx, c);
+    config.buffer.insert_char(config.cy, config.cx, c);
     config.cx += 1;
     config.dirty = true;
+    Ok(())
 }
 
-fn insert_newline(config: &mut EditorConfig) {
+fn insert_newline(config: &mut EditorConfig) -> Result<()> {
+    if refuse_edit(config)? {
+        return Ok(());
+    }
+    config.mark = None;
+    rows_changed(config, config.cy, 1);
     if config.cx == 0 {
-        insert_row(config, config.cy, "");
+        config.buffer.insert_row(config.cy, "");
     } else {
-        let content = config.row[config.cy].content.clone();
-        insert_row(config, config.cy + 1, &content[config.cx..]);
-        config.row[config.cy].content.truncate(config.cx);
-        update_row(config.syntax, &mut config.row[config.cy]);
+        config.buffer.split_row(config.cy, config.cx);
     }
     config.cy += 1;
     config.cx = 0;
     config.dirty = true;
+    Ok(())
 }
 
-fn del_char(config: &mut EditorConfig) {
-    if config.cy == config.row.len() {
-        return;
+fn del_char(config: &mut EditorConfig) -> Result<()> {
+    if refuse_edit(config)? || config.cy == config.buffer.len() {
+        return Ok(());
     }
 
     if config.cx == 0 && config.cy == 0 {
-        return;
+        return Ok(());
     }
+    config.mark = None;
 
     if config.cx > 0 {
-        let row = &mut config.row[config.cy];
-        row_del_char(config.syntax, row, config.cx - 1);
+        // Deleting the opener of an empty pair takes the closer with it.
+        let prev = char_at(config, config.cy, config.cx - 1);
+        let next = char_at(config, config.cy, config.cx);
+        if config.settings.auto_pairs
+            && pairs(config)
+                .iter()
+                .any(|&(open, close)| Some(open) == prev && Some(close) == next)
+        {
+            config.buffer.del_char(config.cy, config.cx);
+        }
+        config.buffer.del_char(config.cy, config.cx - 1);
         config.cx -= 1;
         config.dirty = true;
     } else {
-        config.cx = config.row[config.cy - 1].content.len();
-        let content = config.row[config.cy].content.clone();
-        row_append_string(config.syntax, &mut config.row[config.cy - 1], &content);
-        del_row(config, config.cy);
+        rows_changed(config, config.cy - 1, -1);
+        config.cx = config.buffer.line_len(config.cy - 1);
+        config.buffer.join_rows(config.cy - 1);
         config.cy -= 1;
         config.dirty = true;
     }
+    Ok(())
 }
 
 // File I/O
 
-fn rows_to_string(rows: &[Row]) -> String {
-    rows.iter().fold(String::new(), |mut output, r| {
-        let _ = writeln!(output, "{}", r.content);
-        output
-    })
+/// Replaces the buffer, resetting everything that referred to the old one.
+fn set_buffer(config: &mut EditorConfig, buffer: Buffer, filename: Option<String>) {
+    lsp_close(config);
+    config.buffer = buffer;
+    config.cx = 0;
+    config.cy = 0;
+    config.row_off = 0;
+    config.wrap_off = 0;
+    config.col_off = 0;
+    config.mark = None;
+    config.folds.clear();
+    config.indent = config.buffer.indentation();
+    config.git_branch = filename
+        .as_deref()
+        .and_then(|f| statusline::git_branch(Path::new(f)));
+    config.read_only = config.view;
+    config.path = filename.as_deref().map(canonical);
+    if let Some(path) = config.path.clone() {
+        if let Some(i) = config.lsp_utf16.iter().position(|p| *p == path) {
+            config.lsp_utf16.remove(i);
+            convert_lsp_columns(config, &path);
+        }
+    }
+    config.events.watch(config.path.clone());
+    config.mtime = filename
+        .as_deref()
+        .and_then(|f| fs::metadata(f).and_then(|m| m.modified()).ok());
+    config.changed_on_disk = false;
+    config.autosave = config.settings.autosave;
+    config.backup = config.settings.backup;
+    config.last_backup = None;
+    config.diagnostic_row = None;
+    config.filename = filename;
+    config.stash = None;
+    select_syntax_highlight(config);
+    config.dirty = false;
 }
 
-fn open(config: &mut EditorConfig, filename: String) {
-    config.filename = Some(filename.to_string());
-    select_syntax_highlight(config);
-    let reader = BufReader::new(File::open(filename).unwrap_or_else(|err| die(err.into())));
-    for line in reader.lines() {
-        let line = line.unwrap_or_else(|err| die(err.into()));
-        insert_row(config, config.row.len(), &line);
+/// Opens `filename`, or starts an empty buffer for it if it doesn't exist.
+fn open(config: &mut EditorConfig, filename: String) -> Result<()> {
+    let buffer = match File::open(&filename) {
+        Ok(file) => Buffer::from_reader(BufReader::new(file), config.settings.tab_stop)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Buffer::new(config.settings.tab_stop),
+        Err(e) => return Err(e.into()),
+    };
+    let writable =
+        !Path::new(&filename).exists() || OpenOptions::new().append(true).open(&filename).is_ok();
+    set_buffer(config, buffer, Some(filename));
+    config.read_only |= !writable;
+    lsp_open(config)
+}
+
+/// Opens the `idx`th file given on the command line, at the line and column
+/// given with it.
+fn open_arg(config: &mut EditorConfig, idx: usize) -> Result<()> {
+    let arg = config.files[idx].clone();
+    if arg.path == "-" {
+        let text = config.stdin.as_deref().unwrap_or_default();
+        let buffer = Buffer::from_reader(text.as_bytes(), config.settings.tab_stop)?;
+        set_buffer(config, buffer, None);
+    } else {
+        open(config, arg.path)?;
     }
-    config.dirty = false;
+    config.file_idx = idx;
+    if let Some(line) = arg.line {
+        config.cy = line.saturating_sub(1).min(config.buffer.len());
+    }
+    if let (Some(col), true) = (arg.col, config.cy < config.buffer.len()) {
+        config.cx = col.saturating_sub(1).min(config.buffer.line_len(config.cy));
+    }
+    Ok(())
 }
 
 fn save(config: &mut EditorConfig) -> Result<()> {
+    if config.read_only && config.filename.is_some() {
+        return set_status_msg(
+            config,
+            "File is read-only; use save-as or sudo-save to write it".to_string(),
+        );
+    }
     if config.filename.is_none() {
-        let f = prompt(config, "Save as (ESC to cancel):", None)?;
+        let f = prompt(
+            config,
+            "Save as (ESC to cancel):",
+            None,
+            Some(commands::complete_path),
+        )?;
         match f {
             None => {
                 set_status_msg(config, "Save aborted".to_string())?;
                 return Ok(());
             }
-            Some(name) => config.filename = Some(name),
+            Some(name) => return write_file(config, name),
         }
     }
+    let filename = config.filename.clone().unwrap();
+    write_file(config, filename)
+}
 
-    select_syntax_highlight(config);
-    let buf = rows_to_string(&config.row);
-    let mut file = OpenOptions::new()
+/// Runs the file type's formatter over the buffer, returning its error if it
+/// failed.
+fn format_buffer(config: &mut EditorConfig) -> Option<String> {
+    let filetype = config.buffer.syntax().map(|s| s.filetype);
+    let command = config.settings.formatter(filetype)?.to_string();
+    let end = (config.buffer.len(), 0);
+    let text = config.buffer.text_between((0, 0), end);
+    let output = match shell::run(&command, &text, || config.events.terminating()) {
+        Ok(output) => output,
+        Err(e) => return Some(e),
+    };
+    if output.stdout == text {
+        return None;
+    }
+    // Nothing at all is more likely a formatter that failed quietly than
+    // the formatted text.
+    if output.stdout.is_empty() {
+        return Some(format!("`{command}` printed nothing"));
+    }
+    // Formatters only move whitespace around, so the cursor is kept on the
+    // char with as many non-whitespace chars before it, or just after the one
+    // it followed if it was at the end of a word.
+    let n = config.buffer.nonblank_before(config.cy, config.cx);
+    let after_word = config
+        .cx
+        .checked_sub(1)
+        .and_then(|cx| char_at(config, config.cy, cx))
+        .is_some_and(|c| !c.is_whitespace())
+        && char_at(config, config.cy, config.cx).is_none_or(char::is_whitespace);
+    config.buffer.replace_between((0, 0), end, &output.stdout);
+    // If the formatter removed some of the chars before the cursor, it goes
+    // after the last one left.
+    let total = config.buffer.nonblank_before(config.buffer.len(), 0);
+    (config.cy, config.cx) = if n < total && !after_word {
+        config.buffer.nth_nonblank(n)
+    } else if total > 0 {
+        let (at, cx) = config
+            .buffer
+            .nth_nonblank(n.saturating_sub(1).min(total - 1));
+        (at, cx + 1)
+    } else {
+        (0, 0)
+    };
+    config.mark = None;
+    config.folds.clear();
+    None
+}
+
+/// Writes the buffer to its file, even if the buffer is read-only.
+/// Formats the buffer and writes it to `filename`, which becomes the
+/// buffer's file name once the write succeeded.
+fn write_file(config: &mut EditorConfig, filename: String) -> Result<()> {
+    // The formatter is the one for the file type of the new name.
+    let renamed = config.filename.as_ref() != Some(&filename);
+    let syntax = config.buffer.syntax();
+    match syntax_for(config, &filename) {
+        Some(syntax) => config.buffer.set_syntax(Some(syntax)),
+        None if renamed => config.buffer.set_syntax(None),
+        None => {}
+    }
+    let format_error = if config.read_only {
+        None
+    } else {
+        format_buffer(config)
+    };
+    if config.settings.strip_trailing_whitespace
+        && !config.read_only
+        && config.buffer.strip_trailing_whitespace() > 0
+    {
+        if config.cy < config.buffer.len() {
+            config.cx = config.cx.min(config.buffer.line_len(config.cy));
+        }
+        config.mark = None;
+    }
+    config.indent = config.buffer.indentation();
+    let msg = match write_buffer(config, filename) {
+        Ok(bytes) => match format_error {
+            Some(e) => format!("{bytes} bytes writen to disk unformatted; {e}"),
+            None => format!("{} bytes writen to disk", bytes),
+        },
+        Err(e) => {
+            if renamed {
+                config.buffer.set_syntax(syntax);
+            }
+            return set_status_msg(config, e);
+        }
+    };
+    set_status_msg(config, msg)?;
+    lsp_save(config)
+}
+
+/// Writes the buffer as it is to `filename`, backing the file up first if
+/// that is on, and makes it the buffer's file. Returns the number of bytes
+/// written, or what went wrong.
+fn write_buffer(config: &mut EditorConfig, filename: String) -> Result<usize, String> {
+    let path = Some(canonical(&filename));
+    // A file saved under another name is backed up regardless of when the
+    // last one was.
+    let backup = config.backup
+        && (path != config.path
+            || config
+                .last_backup
+                .is_none_or(|at| at.elapsed() >= KILO_RS_BACKUP_INTERVAL));
+    let bytes = write_backed_up(config, &filename, backup, |file| {
+        config.buffer.write_to(io::BufWriter::new(file))
+    })
+    .map_err(|e| format!("Can't save! {e}"))?;
+    if backup {
+        config.last_backup = Some(Instant::now());
+    }
+    config.dirty = false;
+    config.changed_on_disk = false;
+    config.git_branch = statusline::git_branch(Path::new(&filename));
+    config.mtime = fs::metadata(&filename).and_then(|m| m.modified()).ok();
+    if path != config.path {
+        // Saved under another name, which the server has to be told about as
+        // a new file.
+        lsp_close(config);
+        config.path = path;
+        config.events.watch(config.path.clone());
+    }
+    config.filename = Some(filename);
+    Ok(bytes)
+}
+
+/// Truncates `filename` and writes it with `write`, first backing it up if
+/// `backup` and it exists. Returns the number of bytes written, or what went
+/// wrong.
+fn write_backed_up(
+    config: &EditorConfig,
+    filename: &str,
+    backup: bool,
+    write: impl FnOnce(File) -> io::Result<usize>,
+) -> Result<usize, String> {
+    if backup && Path::new(filename).exists() {
+        if let Err(e) = self::backup(config, filename) {
+            return Err(format!(
+                "Backing up failed: {e}; toggle-backup to save without one"
+            ));
+        }
+    }
+    let file = match OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .truncate(true)
-        .open(config.filename.as_ref().unwrap())
-        .unwrap_or_else(|err| die(err.into()));
-    match file.write(buf.as_bytes()) {
-        Ok(bytes) => {
-            set_status_msg(config, format!("{} bytes writen to disk", bytes))?;
-            config.dirty = false;
-        }
-        Err(e) => set_status_msg(config, format!("Can't save! I/O error: {}", e))?,
+        .open(filename)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Err(format!("{e}; try sudo-save"));
+        }
+        Err(e) => return Err(format!("I/O error: {e}")),
+    };
+    write(file).map_err(|e| format!("I/O error: {e}"))
+}
+
+/// Copies `filename` as it is on disk to `FILE~`, or to `backup_dir` under
+/// its full path with `%` for `/` and a UTC timestamp.
+fn backup(config: &EditorConfig, filename: &str) -> io::Result<()> {
+    let backup = match &config.settings.backup_dir {
+        None => PathBuf::from(format!("{filename}~")),
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            let name = canonical(filename).to_string_lossy().replace('/', "%");
+            dir.join(format!("{name}.{}~", timestamp(SystemTime::now())))
+        }
     };
+    fs::copy(filename, backup)?;
     Ok(())
 }
 
+/// `time` in UTC as `YYYYMMDD-HHMMSS`.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // The civil date of a day number, as in Howard Hinnant's `civil_from_days`,
+    // with years starting in March so that leap days come last.
+    let z = days + 719468;
+    let (era, doe) = (z.div_euclid(146097), z.rem_euclid(146097));
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Saves the buffer if autosave is on for it and that needs nothing from the
+/// user: it has a name, may be edited, and nobody else changed the file since
+/// it was opened or saved. Unlike `save` it neither formats the buffer nor
+/// strips whitespace, which would move text around while the user types.
+fn autosave(config: &mut EditorConfig) -> Result<()> {
+    if !config.autosave || !config.dirty || config.read_only || config.changed_on_disk {
+        return Ok(());
+    }
+    let Some(filename) = config.filename.clone() else {
+        return Ok(());
+    };
+    let mtime = fs::metadata(&filename).and_then(|m| m.modified()).ok();
+    if mtime.is_some() && mtime != config.mtime {
+        return Ok(());
+    }
+    match write_buffer(config, filename) {
+        Ok(_) => lsp_save(config),
+        Err(e) => set_status_msg(config, e),
+    }
+}
+
+/// Writes the buffer to `path` through `sudo tee`, for files the user has no
+/// permission to write. The terminal is handed back while it runs so that
+/// sudo can ask for a password.
+fn sudo_save(config: &mut EditorConfig, path: &str) -> Result<()> {
+    config.events.pause_input();
+    leave_terminal()?;
+    println!("Writing {path} with sudo");
+    let status = process::Command::new("sudo")
+        .args(["tee", "--", path])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+            let written = config.buffer.write_to(child.stdin.take().unwrap());
+            let status = child.wait()?;
+            written.map(|bytes| (bytes, status))
+        });
+    enter_terminal()?;
+    config.events.resume_input();
+    match status {
+        Ok((bytes, status)) if status.success() => {
+            if config.filename.as_deref() == Some(path) {
+                config.dirty = false;
+                config.mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+            }
+            set_status_msg(config, format!("{bytes} bytes written to {path} with sudo"))
+        }
+        Ok((_, status)) => set_status_msg(config, format!("sudo-save: sudo tee {status}")),
+        Err(e) => set_status_msg(config, format!("sudo-save: {e}")),
+    }
+}
+
+/// Writes the buffer to `FILE.save` (`kilo_rs.save` if it has no name), or
+/// `FILE.save.N` if that exists, returning the path written.
+fn emergency_save(config: &EditorConfig) -> io::Result<String> {
+    let base = format!("{}.save", config.filename.as_deref().unwrap_or("kilo_rs"));
+    let path = (0..)
+        .map(|n| match n {
+            0 => base.clone(),
+            n => format!("{base}.{n}"),
+        })
+        .find(|path| !Path::new(path).exists())
+        .unwrap();
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    config.buffer.write_to(io::BufWriter::new(file))?;
+    Ok(path)
+}
+
+/// Reloads the file after another program modified it at `mtime`, unless
+/// that would lose changes made here, which saving would overwrite instead.
+fn file_changed(config: &mut EditorConfig, path: PathBuf, mtime: SystemTime) -> Result<()> {
+    if config.path.as_ref() != Some(&path) || config.mtime == Some(mtime) {
+        return Ok(());
+    }
+    config.mtime = Some(mtime);
+    let Some(filename) = config.filename.clone() else {
+        return Ok(());
+    };
+    if config.dirty {
+        config.changed_on_disk = true;
+        return set_status_msg(
+            config,
+            "File changed on disk! Saving will overwrite those changes".to_string(),
+        );
+    }
+    // Reloading keeps the cursor and what the user toggled for the buffer.
+    let (cy, cx) = (config.cy, config.cx);
+    let (read_only, autosave, backup) = (config.read_only, config.autosave, config.backup);
+    if let Err(e) = open(config, filename) {
+        return set_status_msg(config, format!("File changed on disk, can't reload: {e}"));
+    }
+    (config.read_only, config.autosave, config.backup) = (read_only, autosave, backup);
+    config.cy = cy.min(config.buffer.len());
+    if config.cy < config.buffer.len() {
+        config.cx = cx.min(config.buffer.line_len(config.cy));
+    }
+    set_status_msg(config, "File changed on disk; reloaded".to_string())
+}
+
 // Find
 
 fn find_callback(config: &mut EditorConfig, query: &str, code: KeyCode) {
     static mut LAST_MATCH: isize = -1;
     static mut DIRECTION: i8 = 1;
 
+    config.search_match = None;
+
     if code == KeyCode::Enter {
         unsafe {
             LAST_MATCH = -1;
@@ -496,7 +973,7 @@ fn find_callback(config: &mut EditorConfig, query: &str, code: KeyCode) {
         LAST_MATCH
     };
 
-    let row_len = config.row.len();
+    let row_len = config.buffer.len();
     for _ in 0..row_len {
         current += unsafe { DIRECTION as isize };
         if current == -1 {
@@ -505,12 +982,19 @@ fn find_callback(config: &mut EditorConfig, query: &str, code: KeyCode) {
             current = 0;
         }
 
-        let row = &mut config.row[current as usize];
-        if let Some(pos) = row.render.find(&query) {
+        let at = current as usize;
+        let line = config.buffer.line(at).to_string();
+        if let Some(pos) = line.find(query) {
             unsafe { LAST_MATCH = current }
-            config.cy = current as usize;
-            config.cx = row_rx_to_cx(row, pos);
+            config.cy = at;
+            config.cx = line[..pos].chars().count();
             config.row_off = row_len;
+
+            let start = config.buffer.cx_to_rx(at, config.cx);
+            let end = config
+                .buffer
+                .cx_to_rx(at, config.cx + query.chars().count());
+            config.search_match = Some((at, start, end));
             break;
         }
     }
@@ -521,163 +1005,793 @@ fn find(config: &mut EditorConfig) -> Result<()> {
     let saved_cy = config.cy;
     let saved_col_off = config.col_off;
     let saved_row_off = config.row_off;
+    let saved_wrap_off = config.wrap_off;
 
     let query = prompt(
         config,
         "Search (Use ESC/Arrows/Enter):",
         Some(Box::new(find_callback)),
+        None,
     )?;
     if query.is_none() {
         config.cx = saved_cx;
         config.cy = saved_cy;
         config.col_off = saved_col_off;
         config.row_off = saved_row_off;
+        config.wrap_off = saved_wrap_off;
     }
     Ok(())
 }
 
-// Output
+// Folding
 
-fn scroll(config: &mut EditorConfig) {
-    config.rx = if config.cy < config.row.len() {
-        let row = &config.row[config.cy];
-        row_cx_to_rx(row, config.cx)
-    } else {
-        0
-    };
+/// Last row hidden by the closed fold starting at row `at`, if there is one.
+fn fold_end(config: &EditorConfig, at: usize) -> Option<usize> {
+    config.folds.iter().filter(|f| f.0 == at).map(|f| f.1).max()
+}
 
-    if config.cy < config.row_off {
-        config.row_off = config.cy;
-    }
-    if config.cy >= config.row_off + config.screen_rows {
-        config.row_off = config.cy - config.screen_rows + 1;
+/// Start of the outermost closed fold hiding row `at`, if it is hidden.
+fn hidden_by(config: &EditorConfig, at: usize) -> Option<usize> {
+    config
+        .folds
+        .iter()
+        .filter(|f| f.0 < at && at <= f.1)
+        .map(|f| f.0)
+        .min()
+}
+
+/// The first row after `at` that isn't hidden by a fold.
+fn next_visible(config: &EditorConfig, at: usize) -> usize {
+    let mut next = at + 1;
+    while let Some(start) = hidden_by(config, next) {
+        next = fold_end(config, start).unwrap_or(next) + 1;
     }
-    if config.rx < config.col_off {
-        config.col_off = config.rx;
+    next
+}
+
+/// The last row before `at` that isn't hidden by a fold.
+fn prev_visible(config: &EditorConfig, at: usize) -> usize {
+    let prev = at.saturating_sub(1);
+    hidden_by(config, prev).unwrap_or(prev)
+}
+
+/// Opens the folds hiding row `at`.
+fn reveal(config: &mut EditorConfig, at: usize) {
+    config.folds.retain(|f| !(f.0 < at && at <= f.1));
+}
+
+/// Keeps the closed folds and diagnostics on the same text when a row is
+/// inserted after row `at` (`delta` 1) or the row after it is joined onto it
+/// (`delta` -1). Folds around `at` itself are opened.
+fn rows_changed(config: &mut EditorConfig, at: usize, delta: isize) {
+    config.folds.retain(|f| !(f.0 <= at && at <= f.1));
+    for fold in &mut config.folds {
+        if fold.0 > at {
+            fold.0 = fold.0.saturating_add_signed(delta);
+            fold.1 = fold.1.saturating_add_signed(delta);
+        }
     }
-    if config.rx >= config.col_off + config.screen_cols {
-        config.col_off = config.rx - config.screen_cols + 1;
+    for (path, diagnostic) in &mut config.diagnostics {
+        if config.path.as_ref() == Some(path) && diagnostic.row > at {
+            diagnostic.row = diagnostic.row.saturating_add_signed(delta);
+        }
     }
 }
 
-fn draw_rows(config: &mut EditorConfig, buf: &mut String) -> Result<()> {
-    for y in 0..config.screen_rows {
-        let file_row = y + config.row_off;
-        if file_row >= config.row.len() {
-            if config.row.is_empty() && y == config.screen_rows / 3 {
-                let mut welcome = format!("Kilo-rs editor -- version {KILO_RS_VERSION}");
-                if welcome.len() > config.screen_cols {
-                    welcome.truncate(config.screen_cols);
-                }
-                let mut padding = (config.screen_cols - welcome.len()) / 2;
-                if padding > 0 {
-                    buf.push('~');
-                    padding -= 1
-                }
+// Build
 
-                while padding != 0 {
-                    buf.push(' ');
-                    padding -= 1;
-                }
-                buf.push_str(&welcome);
+/// `path` with symlinks and relative parts resolved, or as it is if it
+/// doesn't exist.
+fn canonical(path: impl AsRef<Path>) -> PathBuf {
+    fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_path_buf())
+}
+
+/// The most severe diagnostic on row `at` of the file.
+fn row_diagnostic(config: &EditorConfig, at: usize) -> Option<&Diagnostic> {
+    config
+        .diagnostics
+        .iter()
+        .filter(|(path, d)| d.row == at && config.path.as_ref() == Some(path))
+        .map(|(_, d)| d)
+        .min_by_key(|d| d.severity)
+}
+
+/// Shows the diagnostic on the cursor's row once the cursor moves to it.
+/// This is done between keys rather than on every redraw so that it doesn't
+/// replace a prompt.
+fn show_row_diagnostic(config: &mut EditorConfig) -> Result<()> {
+    let row = Some(config.cy).filter(|&cy| cy < config.buffer.len());
+    if row == config.diagnostic_row {
+        return Ok(());
+    }
+    config.diagnostic_row = row;
+    match row.and_then(|at| row_diagnostic(config, at)) {
+        Some(d) => set_status_msg(config, d.message.clone()),
+        None => Ok(()),
+    }
+}
+
+/// Collects the diagnostics of the build that finished with `result`.
+fn build_finished(
+    config: &mut EditorConfig,
+    result: Result<(String, process::ExitStatus), String>,
+) -> Result<()> {
+    let (command, dir) = config.build.take().unwrap_or_default();
+    let msg = match result {
+        Ok((output, status)) => {
+            config.diagnostics = diagnostics::parse(&output)
+                .into_iter()
+                .map(|d| (canonical(dir.join(&d.file)), d))
+                .collect();
+            config.lsp_utf16.clear();
+            config.diagnostic_idx = None;
+            // Keep the summary up rather than the diagnostic under the cursor.
+            config.diagnostic_row = Some(config.cy);
+            let count = |severity| {
+                config
+                    .diagnostics
+                    .iter()
+                    .filter(|(_, d)| d.severity == severity)
+                    .count()
+            };
+            if config.diagnostics.is_empty() && !status.success() {
+                format!("`{command}` failed: {status}")
             } else {
-                buf.push('~');
-            }
-        } else {
-            let mut len = config.row[file_row].rsize.saturating_sub(config.col_off);
-            if len > config.screen_cols {
-                len = config.screen_cols;
+                format!(
+                    "`{command}` finished: {} error(s), {} warning(s)",
+                    count(Severity::Error),
+                    count(Severity::Warning)
+                )
             }
+        }
+        Err(e) => return set_status_msg(config, e),
+    };
+    set_status_msg(config, msg)
+}
 
-            let end = len + config.col_off;
-            let s = config.row[file_row].render[config.col_off..end].to_string();
-            let hl = &mut config.row[file_row].hl[config.col_off..end];
-            let mut current_color: u8 = 0;
+// Language server
 
-            for (j, ch) in s.chars().into_iter().enumerate() {
-                if hl[j] == Highlight::Normal {
-                    if current_color != 0 {
-                        buf.push_str("\x1b[39m");
-                        current_color = 0;
-                    }
-                    buf.push(ch);
-                } else {
-                    let color = hl[j].to_color();
-                    if color != current_color {
-                        current_color = color;
-                        let tmp = format!("\x1b[{}m", color);
-                        buf.push_str(&tmp);
-                    }
-                    buf.push(ch);
+/// Directory of the project `path` is in: the closest one with a
+/// `Cargo.toml` or `.git`, or the file's own directory.
+fn project_root(path: &Path) -> PathBuf {
+    let dir = path.parent().unwrap_or(path);
+    dir.ancestors()
+        .find(|d| d.join("Cargo.toml").exists() || d.join(".git").exists())
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+/// Opens the file in the language server for its type, starting the server
+/// if it isn't running yet or is the one for another type.
+fn lsp_open(config: &mut EditorConfig) -> Result<()> {
+    let Some(path) = config.path.clone().filter(|p| p.is_absolute()) else {
+        return Ok(());
+    };
+    let filetype = config.buffer.syntax().map(|s| s.filetype);
+    let command = config
+        .settings
+        .language_server(filetype)
+        .filter(|command| !config.lsp_failed.iter().any(|c| c == command))
+        .map(str::to_string);
+    if config.lsp.as_ref().map(|c| &c.command) != command.as_ref() {
+        if let Some(client) = config.lsp.take() {
+            client.shutdown();
+        }
+        if let Some(command) = command {
+            let tx = config.events.sender();
+            let wake = move || {
+                let _ = tx.send(events::Event::Lsp);
+            };
+            match lsp::Client::start(&command, &project_root(&path), wake) {
+                Ok(client) => config.lsp = Some(client),
+                Err(e) => {
+                    let msg = format!("Can't start `{command}`: {e}");
+                    config.lsp_failed.push(command);
+                    return set_status_msg(config, msg);
                 }
             }
-            buf.push_str("\x1b[39m");
         }
-
-        buf.push_str("\r\n");
     }
+    let (Some(client), Some(filetype)) = (&mut config.lsp, filetype) else {
+        return Ok(());
+    };
+    let text = config.buffer.text_between((0, 0), (config.buffer.len(), 0));
+    client.did_open(&path, filetype, config.buffer.version(), &text);
+    config.lsp_version = Some(config.buffer.version());
     Ok(())
 }
 
-fn draw_statusbar(config: &EditorConfig, buf: &mut String) {
-    buf.push_str("\x1b[7m");
-    let mut status = format!(
-        "{} - {} lines {}",
-        if let Some(file) = &config.filename {
-            file.as_str()
-        } else {
-            "[No Name]"
-        },
-        config.row.len(),
-        if config.dirty { "(modified)" } else { "" }
-    );
-    let rstatus = format!(
-        "{} | {}/{}",
-        if let Some(syntax) = &config.syntax {
-            syntax.filetype
-        } else {
-            "no ft"
-        },
-        config.cy + 1,
-        config.row.len()
-    );
-    let mut len = status.len();
-    if status.len() > config.screen_cols {
-        len = config.screen_cols;
-    }
-    let rlen = rstatus.len();
-    status.truncate(len);
-    buf.push_str(&status);
-    while len < config.screen_cols {
-        if config.screen_cols - len == rlen {
-            buf.push_str(&rstatus);
-            break;
-        }
-        buf.push(' ');
-        len += 1;
+fn lsp_close(config: &mut EditorConfig) {
+    if let (Some(client), Some(path), Some(_)) = (&mut config.lsp, &config.path, config.lsp_version)
+    {
+        client.did_close(path);
     }
-    buf.push_str("\x1b[m");
-    buf.push_str("\r\n");
+    config.lsp_version = None;
 }
 
-fn draw_messagebar(config: &mut EditorConfig, buf: &mut String) -> Result<()> {
-    buf.push_str("\x1b[K");
-    let msglen = if config.status_msg.len() > config.screen_cols {
-        config.screen_cols
-    } else {
-        config.status_msg.len()
-    };
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    if msglen > 0 && (now - config.status_msg_time < 5) {
-        buf.push_str(&config.status_msg[..msglen]);
+/// Tells the language server the file was saved, opening it there if it was
+/// saved under a new name.
+fn lsp_save(config: &mut EditorConfig) -> Result<()> {
+    if config.lsp_version.is_none() {
+        return lsp_open(config);
+    }
+    lsp_sync(config);
+    if let (Some(client), Some(path)) = (&mut config.lsp, &config.path) {
+        client.did_save(path);
     }
     Ok(())
 }
 
-fn refresh_screen(config: &mut EditorConfig) -> Result<()> {
-    scroll(config);
-
-    let mut buf = String::new();
+/// Sends the language server the text of the buffer if it has changed since
+/// it last got it.
+fn lsp_sync(config: &mut EditorConfig) {
+    let version = config.buffer.version();
+    if config.lsp_version.is_none_or(|v| v == version) {
+        return;
+    }
+    if let (Some(client), Some(path)) = (&mut config.lsp, &config.path) {
+        let text = config.buffer.text_between((0, 0), (config.buffer.len(), 0));
+        client.did_change(path, version, &text);
+        config.lsp_version = Some(version);
+    }
+}
+
+/// Turns the UTF-16 columns of the language server's diagnostics for `path`,
+/// the open file, into char columns.
+fn convert_lsp_columns(config: &mut EditorConfig, path: &Path) {
+    for (p, d) in &mut config.diagnostics {
+        if p == path && d.row < config.buffer.len() {
+            d.col = lsp::char_col(config.buffer.line(d.row).chars(), d.col);
+        }
+    }
+}
+
+/// Handles what the language server sent.
+fn poll_lsp(config: &mut EditorConfig) -> Result<()> {
+    let Some(client) = &mut config.lsp else {
+        return Ok(());
+    };
+    for message in client.poll() {
+        match message {
+            lsp::Message::Response(id, result) => config.lsp_response = Some((id, result)),
+            lsp::Message::Diagnostics(path, diagnostics) => {
+                let path = fs::canonicalize(&path).unwrap_or(path);
+                config.diagnostics.retain(|(p, _)| *p != path);
+                config.lsp_utf16.retain(|p| *p != path);
+                config
+                    .diagnostics
+                    .extend(diagnostics.into_iter().map(|d| (path.clone(), d)));
+                if config.path.as_ref() == Some(&path) {
+                    convert_lsp_columns(config, &path);
+                } else {
+                    config.lsp_utf16.push(path);
+                }
+                config.diagnostic_idx = None;
+            }
+            lsp::Message::Show(msg) => set_status_msg(config, msg)?,
+            lsp::Message::Exited(msg) => {
+                // A server that dies before it is initialized, most often
+                // because its command doesn't exist, would only die again.
+                if let Some(client) = config.lsp.take() {
+                    if !client.initialized() {
+                        config.lsp_failed.push(client.command);
+                    }
+                }
+                config.lsp_version = None;
+                set_status_msg(config, msg)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Position of the cursor as the language server counts it.
+fn lsp_position(config: &EditorConfig) -> lsp::Position {
+    let character = if config.cy < config.buffer.len() {
+        lsp::utf16_col(config.buffer.line(config.cy).chars(), config.cx)
+    } else {
+        0
+    };
+    lsp::Position {
+        line: config.cy,
+        character,
+    }
+}
+
+/// Sends the language server a request about the cursor position with
+/// `send` and waits for the result, which Esc gives up on. `name` is shown
+/// in messages about it.
+fn lsp_request(
+    config: &mut EditorConfig,
+    name: &str,
+    send: impl FnOnce(&mut lsp::Client, &Path, lsp::Position) -> u64,
+) -> Result<Option<Value>> {
+    lsp_sync(config);
+    let position = lsp_position(config);
+    let (Some(client), Some(path), Some(_)) = (&mut config.lsp, &config.path, config.lsp_version)
+    else {
+        set_status_msg(config, format!("{name}: no language server for this file"))?;
+        return Ok(None);
+    };
+    let id = send(client, path, position);
+    let msg = format!("{name}: waiting for `{}` (ESC to cancel)", client.command);
+    set_prompt_msg(config, msg);
+    refresh_screen(config)?;
+    loop {
+        match config.lsp_response.take() {
+            Some((i, result)) if i == id => {
+                set_status_msg(config, String::new())?;
+                return match result {
+                    Ok(result) => Ok(Some(result)),
+                    Err(e) => set_status_msg(config, format!("{name}: {e}")).map(|_| None),
+                };
+            }
+            _ if config.lsp.is_none() => return Ok(None),
+            _ => {}
+        }
+        if let Some(Event::Key(key)) = next_event(config)? {
+            if key.code == KeyCode::Esc {
+                set_status_msg(config, format!("{name}: cancelled"))?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Moves the cursor to `location`, opening its file if it is another one.
+fn goto_location(config: &mut EditorConfig, location: &lsp::Location) -> Result<()> {
+    let path = fs::canonicalize(&location.path).unwrap_or(location.path.clone());
+    if config.path.as_ref() != Some(&path) {
+        if !confirm_discard(config)? {
+            return Ok(());
+        }
+        if let Err(e) = open(config, location.path.to_string_lossy().into_owned()) {
+            return set_status_msg(config, format!("Can't open file: {e}"));
+        }
+    }
+    config.cy = location.position.line.min(config.buffer.len());
+    config.cx = if config.cy < config.buffer.len() {
+        lsp::char_col(
+            config.buffer.line(config.cy).chars(),
+            location.position.character,
+        )
+    } else {
+        0
+    };
+    reveal(config, config.cy);
+    Ok(())
+}
+
+// Output
+
+/// Columns taken by diagnostic signs, which are only shown while the file
+/// has diagnostics.
+fn sign_width(config: &EditorConfig) -> usize {
+    let path = config.path.as_ref();
+    if config.diagnostics.iter().any(|(p, _)| Some(p) == path) {
+        2
+    } else {
+        0
+    }
+}
+
+/// Columns taken by the gutter of signs and line numbers, including its
+/// trailing space.
+fn gutter_width(config: &EditorConfig) -> usize {
+    let signs = sign_width(config);
+    if config.settings.line_numbers == LineNumbers::Off {
+        return signs;
+    }
+    signs + config.buffer.len().max(1).to_string().len() + 1
+}
+
+/// Columns left for text once the gutter is drawn.
+fn text_cols(config: &EditorConfig) -> usize {
+    config
+        .screen_cols
+        .saturating_sub(gutter_width(config))
+        .max(1)
+}
+
+/// Render columns at which each screen line of a soft-wrapped row starts.
+/// Lines break after the last space that fits in `width` columns, or mid-word
+/// when a word is longer than a whole line. A row exactly `width` columns long
+/// gets an empty last line so the cursor has somewhere to sit at its end.
+fn wrap_points(render: &str, width: usize) -> Vec<usize> {
+    let chars: Vec<char> = render.chars().collect();
+    let mut starts = vec![0];
+    let mut start = 0;
+    while chars.len() - start >= width {
+        let limit = start + width;
+        let brk = (start + 1..=limit)
+            .rev()
+            .find(|&i| chars[i - 1] == ' ')
+            .unwrap_or(limit);
+        starts.push(brk);
+        start = brk;
+    }
+    starts
+}
+
+/// Index of the screen line in `starts` that render column `rx` falls on.
+fn wrap_segment(starts: &[usize], rx: usize) -> usize {
+    starts.iter().rposition(|&s| s <= rx).unwrap_or(0)
+}
+
+fn row_wrap_points(config: &EditorConfig, file_row: usize) -> Vec<usize> {
+    if file_row < config.buffer.len() {
+        wrap_points(&config.buffer.render(file_row), text_cols(config))
+    } else {
+        vec![0]
+    }
+}
+
+fn scroll(config: &mut EditorConfig) {
+    config.rx = if config.cy < config.buffer.len() {
+        config.buffer.cx_to_rx(config.cy, config.cx)
+    } else {
+        0
+    };
+
+    // Anything that lands the cursor inside a fold, like a search or a
+    // jump, opens it.
+    reveal(config, config.cy);
+    if let Some(start) = hidden_by(config, config.row_off) {
+        config.row_off = start;
+    }
+
+    if config.settings.soft_wrap {
+        scroll_wrapped(config);
+        return;
+    }
+    config.wrap_off = 0;
+
+    if config.cy < config.row_off {
+        config.row_off = config.cy;
+    }
+    // The topmost row that still shows the cursor on the last screen line.
+    let mut top = config.cy;
+    for _ in 1..config.screen_rows {
+        if top == 0 {
+            break;
+        }
+        top = prev_visible(config, top);
+    }
+    if config.row_off < top {
+        config.row_off = top;
+    }
+    if config.rx < config.col_off {
+        config.col_off = config.rx;
+    }
+    let cols = text_cols(config);
+    if config.rx >= config.col_off + cols {
+        config.col_off = config.rx - cols + 1;
+    }
+}
+
+/// Keeps the cursor's screen line visible when soft wrapping, moving
+/// `row_off`/`wrap_off` one screen line at a time.
+fn scroll_wrapped(config: &mut EditorConfig) {
+    config.col_off = 0;
+    // A wider text area than the last time leaves the top row fewer lines.
+    config.wrap_off = config
+        .wrap_off
+        .min(row_wrap_points(config, config.row_off).len() - 1);
+    let seg = wrap_segment(&row_wrap_points(config, config.cy), config.rx);
+
+    if config.cy < config.row_off || (config.cy == config.row_off && seg < config.wrap_off) {
+        config.row_off = config.cy;
+        config.wrap_off = seg;
+        return;
+    }
+    // Every row takes at least one screen line, so rows further away than
+    // a screen can be skipped without measuring them.
+    if config.cy - config.row_off > config.screen_rows {
+        config.row_off = config.cy - config.screen_rows;
+        config.wrap_off = 0;
+    }
+
+    if let Some(start) = hidden_by(config, config.row_off) {
+        config.row_off = start;
+    }
+
+    loop {
+        let mut lines = seg + 1;
+        for r in visible_rows(config, config.row_off, config.cy) {
+            lines += row_wrap_points(config, r).len();
+        }
+        lines -= config.wrap_off;
+        if lines <= config.screen_rows {
+            break;
+        }
+        if config.wrap_off + 1 < row_wrap_points(config, config.row_off).len() {
+            config.wrap_off += 1;
+        } else {
+            config.row_off = next_visible(config, config.row_off);
+            config.wrap_off = 0;
+        }
+    }
+}
+
+/// Rows from `from` up to but not including `to` that aren't hidden by folds.
+fn visible_rows(config: &EditorConfig, from: usize, to: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(Some(from), move |&r| Some(next_visible(config, r)))
+        .take_while(move |&r| r < to)
+}
+
+/// Screen line of the cursor relative to the top of the text area.
+fn cursor_screen_row(config: &EditorConfig) -> usize {
+    if !config.settings.soft_wrap {
+        return visible_rows(config, config.row_off, config.cy).count();
+    }
+    let mut y = wrap_segment(&row_wrap_points(config, config.cy), config.rx);
+    for r in visible_rows(config, config.row_off, config.cy) {
+        y += row_wrap_points(config, r).len();
+    }
+    y.saturating_sub(config.wrap_off)
+}
+
+/// Screen column of the cursor, gutter included.
+fn cursor_screen_col(config: &EditorConfig) -> usize {
+    let x = if config.settings.soft_wrap {
+        let starts = row_wrap_points(config, config.cy);
+        config.rx - starts[wrap_segment(&starts, config.rx)]
+    } else {
+        config.rx - config.col_off
+    };
+    x + gutter_width(config)
+}
+
+/// What to draw instead of each render column of row `at` when showing
+/// whitespace: an arrow at the start of every tab, and a dot for trailing and
+/// non-breaking spaces.
+fn whitespace_glyphs(config: &EditorConfig, at: usize) -> Vec<Option<(char, theme::Style)>> {
+    let theme = &config.settings.theme;
+    let line = config.buffer.line(at);
+    let trailing_from = line
+        .chars()
+        .enumerate()
+        .filter(|&(_, c)| !c.is_whitespace())
+        .last()
+        .map_or(0, |(cx, _)| cx + 1);
+    let mut glyphs = Vec::new();
+    for (cx, c) in line.chars().enumerate() {
+        let trailing = cx >= trailing_from;
+        let style = if trailing {
+            theme.trailing_whitespace
+        } else {
+            theme.whitespace
+        };
+        match c {
+            '\t' => {
+                glyphs.push(Some(('→', style)));
+                while glyphs.len() % config.buffer.tab_stop() != 0 {
+                    glyphs.push(Some((' ', style)));
+                }
+            }
+            ' ' if trailing => glyphs.push(Some(('·', style))),
+            '\u{a0}' => glyphs.push(Some(('⍽', theme.trailing_whitespace))),
+            _ => glyphs.push(None),
+        }
+    }
+    glyphs
+}
+
+fn draw_rows(config: &mut EditorConfig, buf: &mut String) -> Result<()> {
+    let signs = sign_width(config);
+    let gutter = gutter_width(config) - signs;
+    let cols = text_cols(config);
+    let mut file_row = config.row_off;
+    let mut seg = config.wrap_off;
+    let mut starts = row_wrap_points(config, file_row);
+    let theme = &config.settings.theme;
+    let depth = config.settings.color_depth;
+    let normal = theme.style(Highlight::Normal).escape(depth);
+    let filetype = config.buffer.syntax().map(|s| s.filetype);
+    let rulers = config.settings.rulers(filetype).to_vec();
+    let ruler = theme::Style {
+        bg: theme.ruler.bg,
+        ..theme.style(Highlight::Normal)
+    }
+    .escape(depth);
+    for y in 0..config.screen_rows {
+        buf.push_str(&normal);
+        if file_row >= config.buffer.len() {
+            if config.buffer.is_empty() && y == config.screen_rows / 3 {
+                let mut welcome = format!("Kilo-rs editor -- version {KILO_RS_VERSION}");
+                if welcome.len() > config.screen_cols {
+                    welcome.truncate(config.screen_cols);
+                }
+                let mut padding = (config.screen_cols - welcome.len()) / 2;
+                if padding > 0 {
+                    buf.push('~');
+                    padding -= 1
+                }
+
+                while padding != 0 {
+                    buf.push(' ');
+                    padding -= 1;
+                }
+                buf.push_str(&welcome);
+            } else {
+                buf.push('~');
+            }
+        } else {
+            if signs > 0 {
+                match row_diagnostic(config, file_row).filter(|_| seg == 0) {
+                    Some(d) => {
+                        let (sign, style) = match d.severity {
+                            Severity::Error => ('E', theme.error),
+                            Severity::Warning => ('W', theme.warning),
+                            Severity::Note => ('I', theme.warning),
+                        };
+                        let _ = write!(buf, "{}{sign}{normal} ", style.escape(depth));
+                    }
+                    None => buf.push_str("  "),
+                }
+            }
+            if gutter > 0 && seg > 0 {
+                buf.push_str(&" ".repeat(gutter));
+            } else if gutter > 0 {
+                let number = match config.settings.line_numbers {
+                    LineNumbers::Relative if file_row != config.cy => file_row.abs_diff(config.cy),
+                    _ => file_row + 1,
+                };
+                let _ = write!(
+                    buf,
+                    "{}{:>2$} {normal}",
+                    theme.gutter.escape(depth),
+                    number,
+                    gutter - 1
+                );
+            }
+
+            let selected = match selection(config) {
+                Some((s, e)) if (s.0..=e.0).contains(&file_row) => {
+                    let from = if file_row == s.0 {
+                        config.buffer.cx_to_rx(file_row, s.1)
+                    } else {
+                        0
+                    };
+                    let to = if file_row == e.0 {
+                        config.buffer.cx_to_rx(file_row, e.1)
+                    } else {
+                        usize::MAX
+                    };
+                    from..to
+                }
+                _ => 0..0,
+            };
+            let fold = fold_end(config, file_row);
+            let glyphs = if config.settings.show_whitespace {
+                whitespace_glyphs(config, file_row)
+            } else {
+                Vec::new()
+            };
+            let row = config.buffer.row(file_row);
+            let (start, end) = if config.settings.soft_wrap {
+                (
+                    starts[seg],
+                    starts.get(seg + 1).copied().unwrap_or(row.rsize),
+                )
+            } else {
+                let start = config.col_off.min(row.rsize);
+                (start, (start + cols).min(row.rsize))
+            };
+            let hl = &row.hl[start..end];
+            let search = match config.search_match {
+                Some((r, s, e)) if r == file_row => s..e,
+                _ => 0..0,
+            };
+            let bracket = match config.bracket_match {
+                Some((r, x)) if r == file_row => Some(x),
+                _ => None,
+            };
+            let normal_style = theme.style(Highlight::Normal);
+            let mut current = normal_style;
+
+            for (j, ch) in row.render.chars().skip(start).take(end - start).enumerate() {
+                let x = start + j;
+                let glyph = glyphs.get(x).copied().flatten();
+                let ch = glyph.map_or(ch, |g| g.0);
+                let style = if search.contains(&x) {
+                    theme.style(Highlight::Match)
+                } else if selected.contains(&x) {
+                    theme.selection
+                } else if let Some((_, style)) = glyph {
+                    style
+                } else if bracket == Some(x) {
+                    theme.style(Highlight::Bracket)
+                } else {
+                    theme.style(hl[j])
+                };
+                let style = if rulers.contains(&x) && !search.contains(&x) && !selected.contains(&x)
+                {
+                    theme::Style {
+                        bg: theme.ruler.bg,
+                        ..style
+                    }
+                } else {
+                    style
+                };
+                if style != current {
+                    current = style;
+                    buf.push_str(&current.escape(depth));
+                }
+                buf.push(ch);
+            }
+            if current != normal_style {
+                buf.push_str(&normal);
+            }
+            let last_seg = !config.settings.soft_wrap || seg + 1 == starts.len();
+            if let (Some(fold_end), true) = (fold, last_seg) {
+                let hidden = fold_end - file_row;
+                let summary = format!(" ... {hidden} line{}", if hidden == 1 { "" } else { "s" });
+                let room = cols.saturating_sub(end - start);
+                let summary: String = summary.chars().take(room).collect();
+                let _ = write!(buf, "{}{summary}{normal}", theme.gutter.escape(depth));
+            } else if last_seg {
+                // Rulers past the end of the row are drawn over padding.
+                let mut x = end;
+                for &r in rulers.iter().filter(|&&r| r >= end && r < start + cols) {
+                    let _ = write!(buf, "{}{ruler} {normal}", " ".repeat(r - x));
+                    x = r + 1;
+                }
+            }
+        }
+
+        buf.push_str("\x1b[K\x1b[m\r\n");
+
+        if config.settings.soft_wrap && seg + 1 < starts.len() {
+            seg += 1;
+        } else {
+            file_row = next_visible(config, file_row);
+            seg = 0;
+            if config.settings.soft_wrap {
+                starts = row_wrap_points(config, file_row);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn draw_statusbar(config: &EditorConfig, buf: &mut String) {
+    let style = config.settings.theme.statusbar;
+    buf.push_str(&style.escape(config.settings.color_depth));
+    let left = statusline::render(config, &config.settings.status_left);
+    let right = statusline::render(config, &config.settings.status_right);
+    buf.push_str(&statusline::layout(&left, &right, config.screen_cols));
+    buf.push_str("\x1b[m");
+    buf.push_str("\r\n");
+}
+
+fn draw_messagebar(config: &mut EditorConfig, buf: &mut String) -> Result<()> {
+    buf.push_str("\x1b[K");
+    // Hover text and other messages from the language server aren't ASCII
+    // only, so they are cut at a char.
+    let msg: String = config.status_msg.chars().take(config.screen_cols).collect();
+    if config
+        .status_msg_expiry
+        .is_none_or(|expiry| Instant::now() < expiry)
+    {
+        buf.push_str(&msg);
+    }
+    Ok(())
+}
+
+fn refresh_screen(config: &mut EditorConfig) -> Result<()> {
+    scroll(config);
+    // The partner only matters if it is on screen, which it can't be when
+    // it is more than a screen of rows away.
+    config.bracket_match = if config.cy < config.buffer.len() {
+        config
+            .buffer
+            .matching_bracket(config.cy, config.rx, config.screen_rows)
+    } else {
+        None
+    };
+
+    let mut buf = String::new();
 
     config.stdout.queue(cursor::Hide)?;
     config.stdout.queue(Clear(ClearType::All))?;
@@ -689,34 +1803,58 @@ fn refresh_screen(config: &mut EditorConfig) -> Result<()> {
 
     config.stdout.queue(style::Print(buf))?;
     config.stdout.queue(cursor::MoveTo(
-        (config.rx - config.col_off) as u16,
-        (config.cy - config.row_off) as u16,
+        cursor_screen_col(config) as u16,
+        cursor_screen_row(config) as u16,
     ))?;
     config.stdout.queue(cursor::Show)?;
     config.stdout.flush()?;
     Ok(())
 }
 
+/// Shows `msg` in the message bar for `message_timeout` seconds.
 fn set_status_msg(config: &mut EditorConfig, msg: String) -> Result<()> {
+    let expiry = Instant::now() + Duration::from_secs(config.settings.message_timeout);
     config.status_msg = msg;
-    config.status_msg_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    config.status_msg_expiry = Some(expiry);
+    config.events.set_timer(events::Timer::Message, expiry);
     Ok(())
 }
 
+/// Shows `msg` in the message bar until another message replaces it, for
+/// prompts that wait on the user.
+fn set_prompt_msg(config: &mut EditorConfig, msg: String) {
+    config.status_msg = msg;
+    config.status_msg_expiry = None;
+    config.events.cancel_timer(events::Timer::Message);
+}
+
 // Input
 
 fn prompt(
     config: &mut EditorConfig,
     p: &str,
     callback: Option<Callback>,
+    completer: Option<Completer>,
 ) -> Result<Option<String>> {
     let mut buf = String::new();
+    // Candidates offered by the completer and the one currently shown; Tab
+    // cycles through them and any other key starts over.
+    let mut completions: Vec<String> = Vec::new();
+    let mut completion_idx = 0;
 
     loop {
-        set_status_msg(config, format!("{} {}", p, buf))?;
+        let hint = if completions.len() > 1 {
+            format!("  [{}/{}]", completion_idx + 1, completions.len())
+        } else {
+            String::new()
+        };
+        set_prompt_msg(config, format!("{} {}{}", p, buf, hint));
         refresh_screen(config)?;
-        let event = read()?;
+        let event = read_event(config)?;
         if let Event::Key(key) = event {
+            if key.code != KeyCode::Tab {
+                completions.clear();
+            }
             match key.code {
                 KeyCode::Backspace => {
                     buf.pop();
@@ -728,13 +1866,24 @@ fn prompt(
                     }
                     return Ok(None);
                 }
-                KeyCode::Enter => {
-                    if !buf.is_empty() {
-                        set_status_msg(config, String::new())?;
-                        if let Some(callback) = callback.as_ref() {
-                            callback(config, &buf, key.code);
+                KeyCode::Enter if !buf.is_empty() => {
+                    set_status_msg(config, String::new())?;
+                    if let Some(callback) = callback.as_ref() {
+                        callback(config, &buf, key.code);
+                    }
+                    return Ok(Some(buf));
+                }
+                KeyCode::Tab => {
+                    if let Some(complete) = completer {
+                        if completions.is_empty() {
+                            completions = complete(config, &buf);
+                            completion_idx = 0;
+                        } else {
+                            completion_idx = (completion_idx + 1) % completions.len();
+                        }
+                        if let Some(c) = completions.get(completion_idx) {
+                            buf = c.clone();
                         }
-                        return Ok(Some(buf));
                     }
                 }
                 KeyCode::Char(c) if !c.is_control() => buf.push(c),
@@ -749,147 +1898,667 @@ fn prompt(
     }
 }
 
+/// Moves the cursor up or down one screen line of a soft-wrapped buffer,
+/// keeping its column within the screen line where possible.
+fn move_visual(config: &mut EditorConfig, key: KeyCode) {
+    if config.cy >= config.buffer.len() {
+        if key == KeyCode::Up && config.cy > 0 {
+            config.cy = prev_visible(config, config.cy);
+            let starts = row_wrap_points(config, config.cy);
+            let rx = starts[starts.len() - 1];
+            config.cx = config.buffer.rx_to_cx(config.cy, rx);
+        }
+        return;
+    }
+
+    let rx = config.buffer.cx_to_rx(config.cy, config.cx);
+    let starts = row_wrap_points(config, config.cy);
+    let seg = wrap_segment(&starts, rx);
+    let col = rx - starts[seg];
+
+    let prev = prev_visible(config, config.cy);
+    let next = next_visible(config, config.cy);
+    let (target_row, seg) = match key {
+        KeyCode::Up if seg > 0 => (config.cy, seg - 1),
+        KeyCode::Up if config.cy > 0 => (prev, row_wrap_points(config, prev).len() - 1),
+        KeyCode::Down if seg + 1 < starts.len() => (config.cy, seg + 1),
+        KeyCode::Down if next < config.buffer.len() => (next, 0),
+        KeyCode::Down => {
+            config.cy = config.buffer.len();
+            config.cx = 0;
+            return;
+        }
+        _ => return,
+    };
+
+    let starts = row_wrap_points(config, target_row);
+    let rsize = config
+        .buffer
+        .cx_to_rx(target_row, config.buffer.line_len(target_row));
+    let last = match starts.get(seg + 1) {
+        Some(&next) => next - 1,
+        None => rsize,
+    };
+    let rx = (starts[seg] + col).min(last);
+    config.cy = target_row;
+    config.cx = if rx >= rsize {
+        config.buffer.line_len(target_row)
+    } else {
+        config.buffer.rx_to_cx(target_row, rx)
+    };
+}
+
 fn move_cursor(config: &mut EditorConfig, key: KeyCode) {
-    let row = if config.cy >= config.row.len() {
+    if config.settings.soft_wrap && matches!(key, KeyCode::Up | KeyCode::Down) {
+        move_visual(config, key);
+        return;
+    }
+
+    let row_len = if config.cy >= config.buffer.len() {
         None
     } else {
-        Some(&config.row[config.cy])
+        Some(config.buffer.line_len(config.cy))
     };
     match key {
         KeyCode::Left => {
             if config.cx != 0 {
                 config.cx -= 1;
             } else if config.cy > 0 {
-                config.cy -= 1;
-                config.cx = config.row[config.cy].content.len();
+                config.cy = prev_visible(config, config.cy);
+                config.cx = config.buffer.line_len(config.cy);
             }
         }
         KeyCode::Right => {
-            if row.is_some_and(|r| r.content.len() > config.cx) {
+            if row_len.is_some_and(|len| len > config.cx) {
                 config.cx += 1;
-            } else if row.is_some_and(|r| r.content.len() == config.cx) {
-                config.cy += 1;
+            } else if row_len.is_some_and(|len| len == config.cx) {
+                config.cy = next_visible(config, config.cy);
                 config.cx = 0;
             }
         }
         KeyCode::Up => {
             if config.cy != 0 {
-                config.cy -= 1;
+                config.cy = prev_visible(config, config.cy);
             }
         }
         KeyCode::Down => {
-            if config.row.len() > config.cy {
-                config.cy += 1;
+            if config.buffer.len() > config.cy {
+                config.cy = next_visible(config, config.cy).min(config.buffer.len());
             }
         }
-        _ => todo!("Wait What!?"),
+        // Only the arrow keys move the cursor.
+        _ => return,
     }
 
-    let row = if config.cy >= config.row.len() {
+    let row_len = if config.cy >= config.buffer.len() {
         None
     } else {
-        Some(&config.row[config.cy])
+        Some(config.buffer.line_len(config.cy))
     };
-    if row.is_some_and(|r| config.cx > r.content.len()) {
-        config.cx = row.unwrap().content.len();
+    if let Some(len) = row_len {
+        if config.cx > len {
+            config.cx = len;
+        }
     }
 }
 
+fn page_move(config: &mut EditorConfig, key: KeyCode) {
+    if key == KeyCode::PageUp {
+        config.cy = config.row_off;
+    } else {
+        config.cy = config.row_off;
+        for _ in 1..config.screen_rows {
+            config.cy = next_visible(config, config.cy);
+        }
+        if config.cy > config.buffer.len() {
+            config.cy = config.buffer.len();
+        }
+    }
+
+    let mut times = config.screen_rows;
+    while times != 0 {
+        move_cursor(
+            config,
+            if key == KeyCode::PageUp {
+                KeyCode::Up
+            } else {
+                KeyCode::Down
+            },
+        );
+        times -= 1;
+    }
+}
+
+fn quit(config: &mut EditorConfig) -> Result<()> {
+    if config.dirty && config.quit_times > 0 {
+        let key = config
+            .settings
+            .key_for("quit")
+            .unwrap_or_else(|| "quit".to_string());
+        set_status_msg(
+            config,
+            format!(
+                "WARNING!! File has unsaved changes. \
+                Press {} {} more times to quit.",
+                key, config.quit_times
+            ),
+        )?;
+        config.quit_times -= 1;
+        return Ok(());
+    }
+    if let Some(client) = config.lsp.take() {
+        client.shutdown();
+    }
+    leave_terminal()?;
+    std::process::exit(0);
+}
+
 fn process_keypress(config: &mut EditorConfig) -> Result<()> {
-    static mut QUIT_TIMES: u8 = KILO_RS_QUIT_TIMES;
-    let event = read()?;
+    let event = read_event(config)?;
     if let Event::Key(key) = event {
-        match key.code {
-            KeyCode::Right | KeyCode::Left | KeyCode::Up | KeyCode::Down => {
-                move_cursor(config, key.code)
-            }
-            KeyCode::PageUp | KeyCode::PageDown => {
-                if key.code == KeyCode::PageUp {
-                    config.cy = config.row_off;
-                } else {
-                    config.cy = config.row_off + config.screen_rows - 1;
-                    if config.cy > config.row.len() {
-                        config.cy = config.row.len();
-                    }
-                }
-
-                let mut times = config.screen_rows;
-                while times != 0 {
-                    move_cursor(
-                        config,
-                        if key.code == KeyCode::PageUp {
-                            KeyCode::Up
-                        } else {
-                            KeyCode::Down
-                        },
-                    );
-                    times -= 1;
+        let command = config
+            .settings
+            .keymap
+            .get(&(key.code, key.modifiers))
+            .cloned();
+        match command {
+            Some(name) => {
+                commands::run_command(config, &name)?;
+                if name == "quit" {
+                    return Ok(());
                 }
             }
-            KeyCode::Enter => insert_newline(config),
-            KeyCode::Home => config.cx = 0,
-            KeyCode::End if config.cy < config.row.len() => {
-                config.cx = config.row[config.cy].content.len()
-            }
-            KeyCode::Backspace => del_char(config),
-            KeyCode::Char('q') if key.modifiers == KeyModifiers::CONTROL => {
-                let q = unsafe { QUIT_TIMES };
-                if config.dirty && q > 0 {
-                    set_status_msg(
-                        config,
-                        format!(
-                            "WARNING!! File has unsaved changes. \
-                    Press Ctrl-Q {} more times to quit.",
-                            q
-                        ),
-                    )?;
-                    unsafe {
-                        QUIT_TIMES -= 1;
-                    }
-                    return Ok(());
+            None => {
+                if let KeyCode::Char(c) = key.code {
+                    insert_char(config, c)?;
                 }
-                disable_raw_mode().unwrap();
-                execute!(
-                    config.stdout,
-                    LeaveAlternateScreen,
-                    cursor::SetCursorStyle::DefaultUserShape
-                )
-                .unwrap();
-                std::process::exit(0);
             }
-            KeyCode::Char('s') if key.modifiers == KeyModifiers::CONTROL => save(config)?,
-            KeyCode::Char('f') if key.modifiers == KeyModifiers::CONTROL => find(config)?,
-            KeyCode::Char(c) => insert_char(config, c),
-            _ => {}
         }
     }
-    unsafe {
-        QUIT_TIMES = KILO_RS_QUIT_TIMES;
-    }
-    Ok(())
+    config.quit_times = config.settings.quit_times;
+    show_row_diagnostic(config)
 }
 
 // Main
 
 fn main() -> Result<()> {
-    let mut config = EditorConfig::new()?;
-    execute!(
-        stdout(),
-        EnterAlternateScreen,
-        cursor::SetCursorStyle::SteadyBlock
-    )?;
-    enable_raw_mode()?;
-    let filename = std::env::args().nth(1);
-    if let Some(filename) = filename {
-        open(&mut config, filename);
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Action::Edit(args)) => args,
+        Ok(cli::Action::Help) => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Ok(cli::Action::Version) => {
+            println!("{}", cli::version());
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("kilo_rs: {e}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    let syntax = match &args.syntax {
+        Some(ft) => match HLDB.iter().find(|s| s.filetype == ft) {
+            Some(syntax) => Some(*syntax),
+            None => {
+                eprintln!("kilo_rs: unknown file type `{ft}`");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let (settings, errors) = settings::load(args.config);
+    let mut config = EditorConfig::new(settings)?;
+    update_size(&mut config)?;
+    config.view = args.read_only;
+    config.syntax = syntax;
+    if args.files.iter().any(|f| f.path == "-") {
+        // Once stdin is drained, crossterm reads keys from /dev/tty instead.
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        config.stdin = Some(text);
     }
-    set_status_msg(
-        &mut config,
-        "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find".to_string(),
-    )
-    .unwrap_or_else(|err| die(err));
-    loop {
-        refresh_screen(&mut config).unwrap_or_else(|err| die(err));
-        process_keypress(&mut config).unwrap_or_else(|err| die(err));
+    config.files = args.files;
+    if !config.files.is_empty() {
+        open_arg(&mut config, 0)?;
+    }
+    // Get the terminal back before the panic message is printed. A panic on
+    // the main thread is caught below to save the buffer; one on another
+    // thread leaves the editor without that thread's events, so it is told
+    // to stop the way a SIGTERM would, which saves too.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = leave_terminal();
+        default_hook(info);
+        if thread::current().name() != Some("main") {
+            let _ = signal_hook::low_level::raise(SIGTERM);
+        }
+    }));
+    enter_terminal()?;
+    let msg = if errors.is_empty() {
+        let mut help = Vec::new();
+        for cmd in ["save", "quit", "find", "command-palette"] {
+            if let Some(key) = config.settings.key_for(cmd) {
+                help.push(format!("{key} = {cmd}"));
+            }
+        }
+        format!("HELP: {}", help.join(" | "))
+    } else {
+        format!("config: {}", errors.join("; "))
+    };
+    set_status_msg(&mut config, msg).unwrap_or_else(|err| die(&config, err));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| loop {
+        refresh_screen(&mut config).unwrap_or_else(|err| die(&config, err));
+        process_keypress(&mut config).unwrap_or_else(|err| die(&config, err));
+    }));
+    if result.is_err() {
+        emergency_exit(&config, 101);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    /// A file of its own in the temporary directory for test `name`.
+    fn temp_file(name: &str, text: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("kilo_rs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        assert_eq!(wrap_points("", 4), [0]);
+        assert_eq!(wrap_points("abc", 4), [0]);
+        // A row that fills the line gets an empty line for the cursor.
+        assert_eq!(wrap_points("abcd", 4), [0, 4]);
+        assert_eq!(wrap_points("ab cd ef", 4), [0, 3, 6]);
+        assert_eq!(wrap_points("abcdefghij", 4), [0, 4, 8]);
+        assert_eq!(wrap_points("a bcdefgh", 4), [0, 2, 6]);
+    }
+
+    #[test]
+    fn scrolls_after_the_text_area_widens() {
+        let settings = Settings {
+            soft_wrap: true,
+            ..Default::default()
+        };
+        let mut config = EditorConfig::new(settings).unwrap();
+        let text = format!("{}\nb\n", "aaaa ".repeat(10));
+        let buffer = Buffer::from_reader(text.as_bytes(), 8).unwrap();
+        set_buffer(&mut config, buffer, None);
+        (config.screen_rows, config.screen_cols) = (3, 10);
+        config.cy = 1;
+        scroll(&mut config);
+        assert_eq!((config.row_off, config.wrap_off), (0, 4));
+        assert_eq!(cursor_screen_row(&config), 2);
+
+        config.screen_cols = 80;
+        scroll(&mut config);
+        assert_eq!((config.row_off, config.wrap_off), (0, 0));
+        assert_eq!(cursor_screen_row(&config), 1);
+    }
+
+    /// An editor on `text` as `fmt.rs`, whose formatter is `formatter`.
+    fn formatting(formatter: &str, text: &str) -> EditorConfig {
+        let mut settings = Settings::default();
+        settings.filetypes.insert(
+            "rust".to_string(),
+            settings::FiletypeSettings {
+                formatter: Some(formatter.to_string()),
+                ..Default::default()
+            },
+        );
+        let mut config = EditorConfig::new(settings).unwrap();
+        let buffer = Buffer::from_reader(text.as_bytes(), 8).unwrap();
+        set_buffer(&mut config, buffer, Some("fmt.rs".to_string()));
+        config
+    }
+
+    #[test]
+    fn keeps_the_cursor_in_the_text_after_formatting() {
+        let mut config = formatting("tr -d ,", "f(a,\n  b,\n");
+        (config.cy, config.cx) = (0, 1);
+        assert_eq!(format_buffer(&mut config), None);
+        assert_eq!((config.cy, config.cx), (0, 1));
+
+        // At the end of the file, where the comma it followed is gone.
+        let mut config = formatting("tr -d ,", "f(a,\n  b,\n");
+        (config.cy, config.cx) = (1, 4);
+        assert_eq!(format_buffer(&mut config), None);
+        assert_eq!(config.buffer.render(1), "  b");
+        assert_eq!((config.cy, config.cx), (1, 3));
+
+        let mut config = formatting("tr -d ,", ",,\n");
+        (config.cy, config.cx) = (0, 2);
+        assert_eq!(format_buffer(&mut config), None);
+        assert_eq!((config.cy, config.cx), (0, 0));
+    }
+
+    #[test]
+    fn keeps_the_buffer_unless_formatting_changes_it() {
+        let mut config = formatting("cat", "f(a);\n");
+        let version = config.buffer.version();
+        assert_eq!(format_buffer(&mut config), None);
+        assert_eq!(config.buffer.version(), version);
+
+        let mut config = formatting("true", "f(a);\n");
+        let version = config.buffer.version();
+        assert_eq!(
+            format_buffer(&mut config).as_deref(),
+            Some("`true` printed nothing")
+        );
+        assert_eq!(config.buffer.render(0), "f(a);");
+        assert_eq!(config.buffer.version(), version);
+    }
+
+    #[test]
+    fn marks_tabs_and_trailing_spaces() {
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        let buffer = Buffer::from_reader("\ta\u{a0}b  \n".as_bytes(), 4).unwrap();
+        set_buffer(&mut config, buffer, None);
+        let glyphs: String = whitespace_glyphs(&config, 0)
+            .iter()
+            .map(|g| g.map_or('_', |(c, _)| c))
+            .collect();
+        assert_eq!(glyphs, "→   _⍽_··");
+        let theme = &config.settings.theme;
+        let styles: Vec<_> = whitespace_glyphs(&config, 0)
+            .into_iter()
+            .flatten()
+            .map(|(_, style)| style)
+            .collect();
+        assert_eq!(styles[0], theme.whitespace);
+        assert_eq!(styles[4..], [theme.trailing_whitespace; 3]);
+
+        (config.screen_rows, config.screen_cols) = (1, 20);
+        let mut buf = String::new();
+        draw_rows(&mut config, &mut buf).unwrap();
+        assert!(!buf.contains('→'));
+        config.settings.show_whitespace = true;
+        let mut buf = String::new();
+        draw_rows(&mut config, &mut buf).unwrap();
+        assert!(buf.contains('→') && buf.contains('⍽') && buf.contains("··"));
+    }
+
+    #[test]
+    fn refuses_edits_to_read_only_buffers() {
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        let buffer = Buffer::from_reader("ab\n".as_bytes(), 8).unwrap();
+        set_buffer(&mut config, buffer, None);
+        config.read_only = true;
+        config.cx = 1;
+        insert_char(&mut config, 'x').unwrap();
+        insert_newline(&mut config).unwrap();
+        del_char(&mut config).unwrap();
+        assert_eq!(config.buffer.to_string(), "ab\n");
+        assert!(!config.dirty);
+        assert_eq!(
+            config.status_msg,
+            "Buffer is read-only; run toggle-read-only to edit it"
+        );
+
+        config.read_only = false;
+        assert!(!refuse_edit(&mut config).unwrap());
+        insert_char(&mut config, 'x').unwrap();
+        assert_eq!(config.buffer.to_string(), "axb\n");
+    }
+
+    #[test]
+    fn opens_unwritable_files_read_only() {
+        let filename = temp_file("writable.txt", "one\n");
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        open(&mut config, filename.clone()).unwrap();
+        assert!(!config.read_only);
+        // Nor are files that don't exist yet.
+        open(&mut config, format!("{filename}.new")).unwrap();
+        assert!(!config.read_only);
+
+        let filename = temp_file("unwritable.txt", "one\n");
+        let mut permissions = fs::metadata(&filename).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&filename, permissions).unwrap();
+        // Root can write it anyway.
+        if File::options().append(true).open(&filename).is_ok() {
+            return;
+        }
+        open(&mut config, filename).unwrap();
+        assert!(config.read_only);
+    }
+
+    #[test]
+    fn keeps_viewed_files_read_only() {
+        let filename = temp_file("viewed.txt", "one\n");
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        config.view = true;
+        open(&mut config, filename.clone()).unwrap();
+        assert!(config.read_only);
+        config.dirty = true;
+        save(&mut config).unwrap();
+        assert_eq!(
+            config.status_msg,
+            "File is read-only; use save-as or sudo-save to write it"
+        );
+
+        // Reloading it after a change on disk keeps it read-only, as does
+        // opening another file.
+        config.dirty = false;
+        fs::write(&filename, "two\n").unwrap();
+        file_changed(&mut config, canonical(&filename), SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(config.buffer.render(0), "two");
+        assert!(config.read_only);
+        open(&mut config, temp_file("viewed2.txt", "")).unwrap();
+        assert!(config.read_only);
+    }
+
+    #[test]
+    fn reads_build_paths_from_where_the_build_ran() {
+        let filename = temp_file("built.rs", "fn main() {}\n");
+        let dir = Path::new(&filename).parent().unwrap().to_path_buf();
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        config.build = Some(("make".to_string(), dir));
+        let output = "built.rs:1:4: error: oops\nmissing.rs:2: warning: hm\n";
+        build_finished(
+            &mut config,
+            Ok((output.to_string(), process::ExitStatus::from_raw(0))),
+        )
+        .unwrap();
+        let paths: Vec<_> = config.diagnostics.iter().map(|(p, _)| p).collect();
+        let dir = canonical(Path::new(&filename).parent().unwrap());
+        assert_eq!(paths, [&canonical(&filename), &dir.join("missing.rs")]);
+        assert_eq!(
+            config.status_msg,
+            "`make` finished: 1 error(s), 1 warning(s)"
+        );
+        assert!(config.build.is_none());
+    }
+
+    #[test]
+    fn converts_language_server_columns_once_the_file_is_open() {
+        let filename = temp_file("utf16.rs", "let s = \"😀\"; x\n");
+        let path = canonical(&filename);
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        let diagnostic = Diagnostic {
+            file: filename.clone(),
+            row: 0,
+            col: 14,
+            severity: Severity::Warning,
+            message: "warning: unused".to_string(),
+        };
+        config.diagnostics = vec![(path.clone(), diagnostic)];
+        config.lsp_utf16 = vec![path];
+        open(&mut config, filename.clone()).unwrap();
+        assert_eq!(config.diagnostics[0].1.col, 13);
+        assert!(config.lsp_utf16.is_empty());
+        // Only once.
+        open(&mut config, filename).unwrap();
+        assert_eq!(config.diagnostics[0].1.col, 13);
+    }
+
+    #[test]
+    fn timestamps_backups_in_utc() {
+        let at = |secs| timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "19700101-000000");
+        assert_eq!(at(951827696), "20000229-123456");
+        assert_eq!(at(951868800), "20000301-000000");
+        // 2100 isn't a leap year.
+        assert_eq!(at(4107542399), "21000228-235959");
+        assert_eq!(at(4107542400), "21000301-000000");
+    }
+
+    /// An editor with `auto_pairs` on `text`.
+    fn pairing(text: &str) -> EditorConfig {
+        let settings = Settings {
+            auto_pairs: true,
+            ..Default::default()
+        };
+        let mut config = EditorConfig::new(settings).unwrap();
+        let buffer = Buffer::from_reader(text.as_bytes(), 8).unwrap();
+        set_buffer(&mut config, buffer, None);
+        config
+    }
+
+    #[test]
+    fn pairs_brackets_and_quotes() {
+        let mut config = pairing("");
+        insert_char(&mut config, '(').unwrap();
+        insert_char(&mut config, '"').unwrap();
+        assert_eq!(config.buffer.to_string(), "(\"\")\n");
+        assert_eq!(config.cx, 2);
+
+        // Not before a word, and no quote after one.
+        let mut config = pairing("ab\n");
+        insert_char(&mut config, '(').unwrap();
+        (config.cy, config.cx) = (0, 3);
+        insert_char(&mut config, '"').unwrap();
+        assert_eq!(config.buffer.to_string(), "(ab\"\n");
+    }
+
+    #[test]
+    fn types_over_closers() {
+        let mut config = pairing("");
+        for c in "(a)".chars() {
+            insert_char(&mut config, c).unwrap();
+        }
+        assert_eq!(config.buffer.to_string(), "(a)\n");
+        assert_eq!(config.cx, 3);
+    }
+
+    #[test]
+    fn deletes_empty_pairs_together() {
+        let mut config = pairing("[]\n");
+        config.cx = 1;
+        del_char(&mut config).unwrap();
+        assert_eq!(config.buffer.to_string(), "\n");
+
+        let mut config = pairing("[a]\n");
+        config.cx = 1;
+        del_char(&mut config).unwrap();
+        assert_eq!(config.buffer.to_string(), "a]\n");
+    }
+
+    #[test]
+    fn wraps_selections_in_pairs() {
+        let mut config = pairing("abc\n");
+        config.mark = Some((0, 1));
+        config.cx = 2;
+        insert_char(&mut config, '{').unwrap();
+        assert_eq!(config.buffer.to_string(), "a{b}c\n");
+        assert_eq!((config.cy, config.cx), (0, 4));
+
+        let mut config = pairing("ab\ncd\n");
+        config.mark = Some((1, 1));
+        config.cx = 1;
+        insert_char(&mut config, '(').unwrap();
+        assert_eq!(config.buffer.to_string(), "a(b\nc)d\n");
+        assert_eq!((config.cy, config.cx), (1, 2));
+
+        // Up to the empty row past the end of the file.
+        let mut config = pairing("abc\n");
+        config.mark = Some((0, 0));
+        config.cy = 1;
+        insert_char(&mut config, '(').unwrap();
+        assert_eq!(config.buffer.to_string(), "(abc)\n");
+        assert_eq!((config.cy, config.cx), (0, 5));
+    }
+
+    #[test]
+    fn gives_up_on_a_missing_language_server() {
+        let command = "/nonexistent/kilo_rs-server";
+        let mut settings = Settings::default();
+        settings.filetypes.insert(
+            "rust".to_string(),
+            settings::FiletypeSettings {
+                language_server: Some(command.to_string()),
+                ..Default::default()
+            },
+        );
+        let mut config = EditorConfig::new(settings).unwrap();
+        let filename = temp_file("missing_server.rs", "fn main() {}\n");
+        open(&mut config, filename.clone()).unwrap();
+        assert!(config.lsp.is_some());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while config.lsp.is_some() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            poll_lsp(&mut config).unwrap();
+        }
+        assert_eq!(config.lsp_failed, [command]);
+
+        open(&mut config, filename).unwrap();
+        assert!(config.lsp.is_none());
+    }
+
+    #[test]
+    fn autosave_keeps_changes_made_on_disk() {
+        let filename = temp_file("autosave.txt", "one\n");
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        open(&mut config, filename.clone()).unwrap();
+        config.autosave = true;
+        insert_char(&mut config, 'x').unwrap();
+        assert!(config.dirty);
+
+        fs::write(&filename, "two\n").unwrap();
+        let mtime = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&filename)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        file_changed(&mut config, canonical(&filename), mtime).unwrap();
+        autosave(&mut config).unwrap();
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "two\n");
+
+        // Saving on purpose overwrites them, after which autosave works again.
+        write_file(&mut config, filename.clone()).unwrap();
+        insert_char(&mut config, 'y').unwrap();
+        autosave(&mut config).unwrap();
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "xyone\n");
+    }
+
+    #[test]
+    fn reloading_keeps_the_buffer_toggles() {
+        let filename = temp_file("reload.txt", "one\n");
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        open(&mut config, filename.clone()).unwrap();
+        (config.autosave, config.backup) = (true, true);
+
+        fs::write(&filename, "two\n").unwrap();
+        let mtime = fs::metadata(&filename).unwrap().modified().unwrap();
+        file_changed(
+            &mut config,
+            canonical(&filename),
+            mtime + Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(config.buffer.render(0), "two");
+        assert!(config.autosave && config.backup);
     }
 }
@@ -0,0 +1,678 @@
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+use crossterm::event::{KeyCode, KeyModifiers};
+use toml::{Table, Value};
+
+use kilo_rs::fold::FoldMethod;
+
+use crate::{
+    commands, statusline,
+    theme::{self, ColorDepth, Theme},
+    KILO_RS_QUIT_TIMES, KILO_RS_TAB_STOP,
+};
+
+pub type KeyBinding = (KeyCode, KeyModifiers);
+
+const DEFAULT_KEYS: [(&str, &str); 26] = [
+    ("left", "move-left"),
+    ("right", "move-right"),
+    ("up", "move-up"),
+    ("down", "move-down"),
+    ("pageup", "page-up"),
+    ("pagedown", "page-down"),
+    ("home", "line-start"),
+    ("end", "line-end"),
+    ("enter", "newline"),
+    ("backspace", "delete-back"),
+    ("ctrl-s", "save"),
+    ("ctrl-q", "quit"),
+    ("ctrl-f", "find"),
+    ("ctrl-p", "command-palette"),
+    ("ctrl-]", "match-bracket"),
+    ("ctrl-space", "toggle-mark"),
+    ("ctrl-t", "fold-toggle"),
+    ("ctrl-z", "suspend"),
+    ("f5", "build"),
+    ("f8", "next-error"),
+    ("shift-f8", "prev-error"),
+    ("ctrl-k", "hover"),
+    ("f12", "goto-definition"),
+    ("shift-f12", "find-references"),
+    ("f2", "rename"),
+    ("ctrl-n", "complete"),
+];
+
+/// Scalar options, settable both from the config file and with `set`.
+pub const OPTIONS: &[&str] = &[
+    "tab_stop",
+    "quit_times",
+    "message_timeout",
+    "line_numbers",
+    "soft_wrap",
+    "theme",
+    "color_depth",
+    "auto_pairs",
+    "fold_method",
+    "show_whitespace",
+    "strip_trailing_whitespace",
+    "rulers",
+    "status_left",
+    "status_right",
+    "build_command",
+    "autosave",
+    "autosave_idle",
+    "backup",
+    "backup_dir",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    Relative,
+}
+
+/// Options that can be set for one file type in a `[filetypes.NAME]` table,
+/// taking precedence over the top-level ones.
+#[derive(Default)]
+pub struct FiletypeSettings {
+    pub rulers: Option<Vec<usize>>,
+    /// Shell command that formats the buffer on its stdin before each save.
+    pub formatter: Option<String>,
+    /// Shell command of the language server for files of the type.
+    pub language_server: Option<String>,
+}
+
+pub struct Settings {
+    pub tab_stop: usize,
+    pub quit_times: u8,
+    pub message_timeout: u64,
+    pub line_numbers: LineNumbers,
+    pub soft_wrap: bool,
+    pub theme: Theme,
+    /// Themes defined in the config file, in addition to the builtin ones.
+    pub themes: Vec<Theme>,
+    pub color_depth: ColorDepth,
+    pub auto_pairs: bool,
+    pub fold_method: FoldMethod,
+    pub show_whitespace: bool,
+    pub strip_trailing_whitespace: bool,
+    /// Columns marked with a ruler, each the first column past a limit.
+    pub rulers: Vec<usize>,
+    pub filetypes: HashMap<String, FiletypeSettings>,
+    /// Templates of the left and right parts of the status bar.
+    pub status_left: String,
+    pub status_right: String,
+    /// Command run by `build`, whose output is read for diagnostics.
+    pub build_command: String,
+    /// Whether buffers are saved without asking, initially; each buffer can
+    /// toggle it.
+    pub autosave: bool,
+    /// Seconds without input after which a modified buffer is autosaved, or
+    /// 0 to autosave only when the terminal loses focus.
+    pub autosave_idle: u64,
+    /// Whether a file is backed up before saving overwrites it, initially.
+    pub backup: bool,
+    /// Directory of timestamped backups, or `None` for `FILE~` next to the
+    /// file.
+    pub backup_dir: Option<PathBuf>,
+    pub keymap: HashMap<KeyBinding, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let keymap = DEFAULT_KEYS
+            .iter()
+            .map(|(key, cmd)| (parse_key(key).unwrap(), cmd.to_string()))
+            .collect();
+        Settings {
+            tab_stop: KILO_RS_TAB_STOP,
+            quit_times: KILO_RS_QUIT_TIMES,
+            message_timeout: 5,
+            line_numbers: LineNumbers::Off,
+            soft_wrap: false,
+            theme: theme::builtin("default").unwrap(),
+            themes: Vec::new(),
+            color_depth: ColorDepth::detect(),
+            auto_pairs: false,
+            fold_method: FoldMethod::Indent,
+            show_whitespace: false,
+            strip_trailing_whitespace: false,
+            rulers: Vec::new(),
+            filetypes: HashMap::new(),
+            status_left: statusline::DEFAULT_LEFT.to_string(),
+            status_right: statusline::DEFAULT_RIGHT.to_string(),
+            build_command: "cargo build --message-format=short".to_string(),
+            autosave: false,
+            autosave_idle: 5,
+            backup: false,
+            backup_dir: None,
+            keymap,
+        }
+    }
+}
+
+impl Settings {
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "tab_stop" => match value.as_integer() {
+                Some(n @ 1..=16) => self.tab_stop = n as usize,
+                _ => {
+                    return Err(format!(
+                        "tab_stop: expected an integer in 1..=16, got {value}"
+                    ))
+                }
+            },
+            "quit_times" => match value.as_integer() {
+                Some(n @ 0..=255) => self.quit_times = n as u8,
+                _ => {
+                    return Err(format!(
+                        "quit_times: expected an integer in 0..=255, got {value}"
+                    ))
+                }
+            },
+            "message_timeout" => match value.as_integer() {
+                Some(n) if n >= 0 => self.message_timeout = n as u64,
+                _ => {
+                    return Err(format!(
+                        "message_timeout: expected a non-negative integer, got {value}"
+                    ))
+                }
+            },
+            "line_numbers" => match value {
+                Value::Boolean(false) => self.line_numbers = LineNumbers::Off,
+                Value::Boolean(true) => self.line_numbers = LineNumbers::Absolute,
+                Value::String(s) if s == "off" => self.line_numbers = LineNumbers::Off,
+                Value::String(s) if s == "absolute" => self.line_numbers = LineNumbers::Absolute,
+                Value::String(s) if s == "relative" => self.line_numbers = LineNumbers::Relative,
+                _ => {
+                    return Err(format!(
+                        "line_numbers: expected \"off\", \"absolute\" or \"relative\", got {value}"
+                    ))
+                }
+            },
+            "soft_wrap" => match value.as_bool() {
+                Some(b) => self.soft_wrap = b,
+                None => return Err(format!("soft_wrap: expected true or false, got {value}")),
+            },
+            "auto_pairs" => match value.as_bool() {
+                Some(b) => self.auto_pairs = b,
+                None => return Err(format!("auto_pairs: expected true or false, got {value}")),
+            },
+            "fold_method" => match value.as_str() {
+                Some("indent") => self.fold_method = FoldMethod::Indent,
+                Some("brackets") => self.fold_method = FoldMethod::Brackets,
+                _ => {
+                    return Err(format!(
+                        "fold_method: expected \"indent\" or \"brackets\", got {value}"
+                    ))
+                }
+            },
+            "show_whitespace" => match value.as_bool() {
+                Some(b) => self.show_whitespace = b,
+                None => {
+                    return Err(format!(
+                        "show_whitespace: expected true or false, got {value}"
+                    ))
+                }
+            },
+            "strip_trailing_whitespace" => match value.as_bool() {
+                Some(b) => self.strip_trailing_whitespace = b,
+                None => {
+                    return Err(format!(
+                        "strip_trailing_whitespace: expected true or false, got {value}"
+                    ))
+                }
+            },
+            "rulers" => self.rulers = parse_rulers(value)?,
+            "build_command" => match value.as_str() {
+                Some(cmd) if !cmd.trim().is_empty() => self.build_command = cmd.to_string(),
+                _ => return Err(format!("build_command: expected a command, got {value}")),
+            },
+            "autosave" => match value.as_bool() {
+                Some(b) => self.autosave = b,
+                None => return Err(format!("autosave: expected true or false, got {value}")),
+            },
+            "autosave_idle" => match value.as_integer() {
+                Some(n) if n >= 0 => self.autosave_idle = n as u64,
+                _ => {
+                    return Err(format!(
+                        "autosave_idle: expected a non-negative integer, got {value}"
+                    ))
+                }
+            },
+            "backup" => match value.as_bool() {
+                Some(b) => self.backup = b,
+                None => return Err(format!("backup: expected true or false, got {value}")),
+            },
+            "backup_dir" => match value.as_str() {
+                Some("") => self.backup_dir = None,
+                Some(dir) => self.backup_dir = Some(expand_home(dir)),
+                None => return Err(format!("backup_dir: expected a path, got {value}")),
+            },
+            "status_left" | "status_right" => {
+                let Some(template) = value.as_str() else {
+                    return Err(format!("{key}: expected a string, got {value}"));
+                };
+                statusline::validate(template).map_err(|e| format!("{key}: {e}"))?;
+                if key == "status_left" {
+                    self.status_left = template.to_string();
+                } else {
+                    self.status_right = template.to_string();
+                }
+            }
+            "theme" => {
+                let name = value.as_str().unwrap_or_default();
+                let theme = self
+                    .themes
+                    .iter()
+                    .find(|t| t.name == name)
+                    .cloned()
+                    .or_else(|| theme::builtin(name));
+                match theme {
+                    Some(theme) => self.theme = theme,
+                    None => return Err(format!("theme: unknown theme {value}")),
+                }
+            }
+            "color_depth" => {
+                let depth = match value {
+                    Value::Integer(n) => ColorDepth::parse(&n.to_string()),
+                    Value::String(s) => ColorDepth::parse(s),
+                    _ => None,
+                };
+                match depth {
+                    Some(depth) => self.color_depth = depth,
+                    None => {
+                        return Err(format!(
+                            "color_depth: expected \"auto\", 16, 256 or \"truecolor\", got {value}"
+                        ))
+                    }
+                }
+            }
+            _ => return Err(format!("unknown option `{key}`")),
+        }
+        Ok(())
+    }
+
+    /// Rulers for files of `filetype`.
+    pub fn rulers(&self, filetype: Option<&str>) -> &[usize] {
+        filetype
+            .and_then(|ft| self.filetypes.get(ft))
+            .and_then(|ft| ft.rulers.as_deref())
+            .unwrap_or(&self.rulers)
+    }
+
+    /// Formatter command for files of `filetype`, if one is configured.
+    pub fn formatter(&self, filetype: Option<&str>) -> Option<&str> {
+        self.filetypes.get(filetype?)?.formatter.as_deref()
+    }
+
+    /// Language server command for files of `filetype`, if one is
+    /// configured.
+    pub fn language_server(&self, filetype: Option<&str>) -> Option<&str> {
+        self.filetypes.get(filetype?)?.language_server.as_deref()
+    }
+
+    /// First key bound to `command`, formatted for display (e.g. `Ctrl-S`).
+    pub fn key_for(&self, command: &str) -> Option<String> {
+        let mut keys: Vec<String> = self
+            .keymap
+            .iter()
+            .filter(|(_, cmd)| cmd.as_str() == command)
+            .map(|(key, _)| key_to_string(*key))
+            .collect();
+        keys.sort();
+        keys.into_iter().next()
+    }
+}
+
+/// `path` with a leading `~/` replaced by the home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("kilo_rs").join("config.toml"));
+    }
+    env::var_os("HOME").map(|home| {
+        PathBuf::from(home)
+            .join(".config")
+            .join("kilo_rs")
+            .join("config.toml")
+    })
+}
+
+/// Loads the user's config file, or `path` if given, on top of the defaults.
+/// A missing default config file is not an error; everything else that goes
+/// wrong is returned as a list of messages so the editor can still start and
+/// report them.
+pub fn load(path: Option<PathBuf>) -> (Settings, Vec<String>) {
+    let mut settings = Settings::default();
+    let mut errors = Vec::new();
+
+    let explicit = path.is_some();
+    let Some(path) = path.or_else(config_path) else {
+        return (settings, errors);
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            return (settings, errors)
+        }
+        Err(e) => {
+            errors.push(format!("{}: {}", path.display(), e));
+            return (settings, errors);
+        }
+    };
+    match text.parse::<Table>() {
+        Ok(table) => apply(&mut settings, &table, &mut errors),
+        Err(e) => errors.push(format!("{}: {}", path.display(), e.message())),
+    }
+    (settings, errors)
+}
+
+fn apply(settings: &mut Settings, table: &Table, errors: &mut Vec<String>) {
+    // User themes have to exist before `theme` can select one of them.
+    if let Some(themes) = table.get("themes") {
+        match themes.as_table() {
+            Some(themes) => settings.themes = theme::parse_themes(themes, errors),
+            None => errors.push("themes: expected a table".to_string()),
+        }
+    }
+
+    for (key, value) in table {
+        match key.as_str() {
+            "themes" => {}
+            "filetypes" => match value.as_table() {
+                Some(filetypes) => apply_filetypes(settings, filetypes, errors),
+                None => errors.push("filetypes: expected a table".to_string()),
+            },
+            "keys" => match value.as_table() {
+                Some(keys) => apply_keys(settings, keys, errors),
+                None => errors.push("keys: expected a table".to_string()),
+            },
+            _ => {
+                if let Err(e) = settings.set(key, value) {
+                    errors.push(e);
+                }
+            }
+        }
+    }
+}
+
+fn apply_filetypes(settings: &mut Settings, filetypes: &Table, errors: &mut Vec<String>) {
+    for (filetype, value) in filetypes {
+        let Some(options) = value.as_table() else {
+            errors.push(format!("filetypes.{filetype}: expected a table"));
+            continue;
+        };
+        let ft = settings.filetypes.entry(filetype.clone()).or_default();
+        for (key, value) in options {
+            let result = match key.as_str() {
+                "rulers" => parse_rulers(value).map(|r| ft.rulers = Some(r)),
+                "formatter" => match value.as_str() {
+                    Some(cmd) if !cmd.trim().is_empty() => {
+                        ft.formatter = Some(cmd.to_string());
+                        Ok(())
+                    }
+                    _ => Err(format!("formatter: expected a command, got {value}")),
+                },
+                "language_server" => match value.as_str() {
+                    Some(cmd) if !cmd.trim().is_empty() => {
+                        ft.language_server = Some(cmd.to_string());
+                        Ok(())
+                    }
+                    _ => Err(format!("language_server: expected a command, got {value}")),
+                },
+                _ => Err(format!("unknown option `{key}`")),
+            };
+            if let Err(e) = result {
+                errors.push(format!("filetypes.{filetype}: {e}"));
+            }
+        }
+    }
+}
+
+/// Parses rulers given as one column, an array of them, or a comma-separated
+/// list as typed after `set rulers=`, into sorted columns without repeats.
+fn parse_rulers(value: &Value) -> Result<Vec<usize>, String> {
+    let err = || format!("rulers: expected columns such as [80, 100], got {value}");
+    let column = |n: i64| usize::try_from(n).ok().filter(|&n| n > 0);
+    let mut rulers: Vec<usize> = match value {
+        Value::Integer(n) => column(*n).map(|n| vec![n]).ok_or_else(err),
+        Value::Array(items) => items
+            .iter()
+            .map(|v| v.as_integer().and_then(column).ok_or_else(err))
+            .collect(),
+        Value::String(s) => s
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.trim().parse().ok().and_then(column).ok_or_else(err))
+            .collect(),
+        _ => Err(err()),
+    }?;
+    rulers.sort_unstable();
+    rulers.dedup();
+    Ok(rulers)
+}
+
+fn apply_keys(settings: &mut Settings, keys: &Table, errors: &mut Vec<String>) {
+    for (key, value) in keys {
+        let Some(binding) = parse_key(key) else {
+            errors.push(format!("keys: unknown key `{key}`"));
+            continue;
+        };
+        match value {
+            Value::String(cmd) if cmd == "none" => {
+                settings.keymap.remove(&binding);
+            }
+            Value::String(cmd) if commands::is_valid(cmd) => {
+                settings.keymap.insert(binding, cmd.clone());
+            }
+            _ => errors.push(format!("keys.{key}: unknown command {value}")),
+        }
+    }
+}
+
+/// Interprets a bare value typed after `set key=`, where strings aren't quoted.
+pub fn parse_value(s: &str) -> Value {
+    if let Ok(n) = s.parse::<i64>() {
+        Value::Integer(n)
+    } else if let Ok(b) = s.parse::<bool>() {
+        Value::Boolean(b)
+    } else {
+        Value::String(s.trim_matches('"').to_string())
+    }
+}
+
+/// Punctuation typed with Ctrl that terminals can't tell from Ctrl-4..7.
+const CTRL_PUNCT: [char; 4] = ['\\', ']', '^', '_'];
+
+/// Parses a key description such as `ctrl-s`, `alt-left`, `pagedown` or `f5`.
+pub fn parse_key(s: &str) -> Option<KeyBinding> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = s;
+    loop {
+        if let Some(r) = rest.strip_prefix("ctrl-") {
+            modifiers |= KeyModifiers::CONTROL;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("alt-") {
+            modifiers |= KeyModifiers::ALT;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("shift-") {
+            modifiers |= KeyModifiers::SHIFT;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+
+    let code = match rest {
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "enter" => KeyCode::Enter,
+        "backspace" => KeyCode::Backspace,
+        "delete" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "tab" => KeyCode::Tab,
+        "esc" => KeyCode::Esc,
+        "space" => KeyCode::Char(' '),
+        f if f.len() > 1 && f.starts_with('f') => KeyCode::F(f[1..].parse().ok()?),
+        _ => {
+            let mut chars = rest.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            // Terminals report Ctrl/Alt chords with the lowercase letter, and
+            // send Ctrl-\ Ctrl-] Ctrl-^ Ctrl-_ as the same bytes as Ctrl-4..7.
+            match CTRL_PUNCT.iter().position(|&p| p == c) {
+                Some(i) if modifiers == KeyModifiers::CONTROL => {
+                    KeyCode::Char((b'4' + i as u8) as char)
+                }
+                _ if modifiers.is_empty() => KeyCode::Char(c),
+                _ => KeyCode::Char(c.to_ascii_lowercase()),
+            }
+        }
+    };
+    Some((code, modifiers))
+}
+
+pub fn key_to_string((code, modifiers): KeyBinding) -> String {
+    let mut s = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        s.push_str("Ctrl-");
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        s.push_str("Alt-");
+    }
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        s.push_str("Shift-");
+    }
+    match code {
+        KeyCode::Char(' ') => s.push_str("Space"),
+        KeyCode::Char(c @ '4'..='7') if modifiers == KeyModifiers::CONTROL => {
+            s.push(CTRL_PUNCT[c as usize - '4' as usize])
+        }
+        KeyCode::Char(c) if !modifiers.is_empty() => s.push(c.to_ascii_uppercase()),
+        KeyCode::Char(c) => s.push(c),
+        KeyCode::F(n) => s.push_str(&format!("F{n}")),
+        code => s.push_str(&format!("{code:?}")),
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modifiers() {
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(parse_key("ctrl-s"), Some((KeyCode::Char('s'), ctrl)));
+        assert_eq!(parse_key("ctrl-S"), Some((KeyCode::Char('s'), ctrl)));
+        assert_eq!(
+            parse_key("ctrl-alt-x"),
+            Some((KeyCode::Char('x'), ctrl | KeyModifiers::ALT))
+        );
+        assert_eq!(
+            parse_key("shift-f8"),
+            Some((KeyCode::F(8), KeyModifiers::SHIFT))
+        );
+        assert_eq!(
+            parse_key("alt-left"),
+            Some((KeyCode::Left, KeyModifiers::ALT))
+        );
+        // Terminals send Ctrl-] as Ctrl-5.
+        assert_eq!(parse_key("ctrl-]"), Some((KeyCode::Char('5'), ctrl)));
+        assert_eq!(
+            parse_key("alt-]"),
+            Some((KeyCode::Char(']'), KeyModifiers::ALT))
+        );
+    }
+
+    #[test]
+    fn parses_named_keys() {
+        let none = KeyModifiers::NONE;
+        assert_eq!(parse_key("pagedown"), Some((KeyCode::PageDown, none)));
+        assert_eq!(parse_key("esc"), Some((KeyCode::Esc, none)));
+        assert_eq!(parse_key("f12"), Some((KeyCode::F(12), none)));
+        assert_eq!(
+            parse_key("ctrl-space"),
+            Some((KeyCode::Char(' '), KeyModifiers::CONTROL))
+        );
+        assert_eq!(parse_key("f"), Some((KeyCode::Char('f'), none)));
+        assert_eq!(parse_key("A"), Some((KeyCode::Char('A'), none)));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for s in [
+            "", "ctrl-", "fx", "f1x", "meta-x", "ctrl-ab", "pgdn", "Ctrl-s",
+        ] {
+            assert_eq!(parse_key(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn formats_keys_back() {
+        for (s, shown) in [
+            ("ctrl-s", "Ctrl-S"),
+            ("ctrl-]", "Ctrl-]"),
+            ("ctrl-space", "Ctrl-Space"),
+            ("shift-f12", "Shift-F12"),
+            ("alt-pageup", "Alt-PageUp"),
+        ] {
+            assert_eq!(key_to_string(parse_key(s).unwrap()), shown);
+        }
+    }
+
+    #[test]
+    fn parses_rulers() {
+        let rulers = |s: &str| parse_rulers(&s.parse::<Value>().unwrap());
+        assert_eq!(rulers("80"), Ok(vec![80]));
+        assert_eq!(rulers("[100, 80]"), Ok(vec![80, 100]));
+        assert_eq!(rulers("[]"), Ok(vec![]));
+        // As typed after `set rulers=`.
+        assert_eq!(rulers("\"120, 80,,80 \""), Ok(vec![80, 120]));
+        assert_eq!(rulers("\"\""), Ok(vec![]));
+    }
+
+    #[test]
+    fn rejects_bad_rulers() {
+        for s in [
+            "0",
+            "-1",
+            "[80, 0]",
+            "[80, \"x\"]",
+            "\"80,x\"",
+            "true",
+            "1.5",
+        ] {
+            let value: Value = s.parse().unwrap();
+            assert_eq!(
+                parse_rulers(&value),
+                Err(format!(
+                    "rulers: expected columns such as [80, 100], got {value}"
+                )),
+                "{s}"
+            );
+        }
+
+        // A bad `set` keeps the rulers there were.
+        let mut settings = Settings::default();
+        settings.set("rulers", &parse_value("72,80")).unwrap();
+        assert!(settings.set("rulers", &parse_value("80,x")).is_err());
+        assert_eq!(settings.rulers, [72, 80]);
+    }
+}
@@ -0,0 +1,180 @@
+//! Running shell commands on the contents of the buffer.
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How often `run` checks whether the command finished or should be given up.
+const POLL: Duration = Duration::from_millis(10);
+
+/// What a command printed.
+pub struct Output {
+    pub stdout: String,
+    /// First line the command printed to stderr, if any.
+    pub message: Option<String>,
+}
+
+/// Starts `command` with `sh -c` in `dir` on another thread, without any
+/// input, and calls `done` with what it printed to stderr and stdout and how
+/// it exited.
+pub fn spawn(
+    command: &str,
+    dir: &Path,
+    done: impl FnOnce(Result<(String, ExitStatus), String>) + Send + 'static,
+) {
+    let (cmd, dir) = (command.to_string(), dir.to_path_buf());
+    thread::spawn(move || {
+        let result = Command::new("sh")
+            .args(["-c", &cmd])
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .output()
+            .map(|output| {
+                let mut text = String::from_utf8_lossy(&output.stderr).into_owned();
+                text.push_str(&String::from_utf8_lossy(&output.stdout));
+                (text, output.status)
+            })
+            .map_err(|e| format!("{cmd}: {e}"));
+        done(result);
+    });
+}
+
+/// Runs `command` with `sh -c`, writing `input` to its stdin. Fails with a
+/// message for the message bar if it can't be started, exits with an error or
+/// prints something other than UTF-8. The command is killed if `interrupted`
+/// returns true before it is done.
+pub fn run(command: &str, input: &str, interrupted: impl Fn() -> bool) -> Result<Output, String> {
+    let err = |e: io::Error| format!("{command}: {e}");
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(err)?;
+    // Every pipe gets a thread of its own, so that a command that prints as
+    // it reads can't fill one while we're blocked on another, and so that
+    // they can be left behind when the command is killed.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let stdout = read_all(child.stdout.take().unwrap());
+    let stderr = read_all(child.stderr.take().unwrap());
+    let status = loop {
+        if interrupted() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("{command}: interrupted"));
+        }
+        if stdout.is_finished() && stderr.is_finished() {
+            if let Some(status) = child.try_wait().map_err(err)? {
+                break status;
+            }
+        }
+        thread::sleep(POLL);
+    };
+    let stdout = stdout.join().unwrap().map_err(err)?;
+    let stderr = stderr.join().unwrap().map_err(err)?;
+
+    let stderr = String::from_utf8_lossy(&stderr);
+    let message = stderr
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(|line| format!("{command}: {line}"));
+    if !status.success() {
+        return Err(message.unwrap_or_else(|| format!("{command}: {status}")));
+    }
+    let stdout = String::from_utf8(stdout).map_err(|_| format!("{command}: output isn't UTF-8"))?;
+    Ok(Output { stdout, message })
+}
+
+/// Reads `pipe` to the end on another thread.
+fn read_all(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        pipe.read_to_end(&mut bytes).map(|_| bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Instant};
+
+    use super::*;
+
+    #[test]
+    fn pipes_input_through() {
+        let output = run("tr a-z A-Z", "abc\n", || false).unwrap();
+        assert_eq!(output.stdout, "ABC\n");
+        assert_eq!(output.message, None);
+    }
+
+    #[test]
+    fn keeps_the_first_line_of_stderr() {
+        let output = run(
+            "echo; echo ' warn ' >&2; echo more >&2; echo out",
+            "",
+            || false,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, "\nout\n");
+        assert_eq!(
+            output.message.as_deref(),
+            Some("echo; echo ' warn ' >&2; echo more >&2; echo out: warn")
+        );
+    }
+
+    #[test]
+    fn fails_on_errors() {
+        assert_eq!(
+            run("echo bad >&2; exit 3", "", || false).err().unwrap(),
+            "echo bad >&2; exit 3: bad"
+        );
+        assert_eq!(
+            run("exit 3", "", || false).err().unwrap(),
+            "exit 3: exit status: 3"
+        );
+        assert_eq!(
+            run("printf '\\377'", "", || false).err().unwrap(),
+            "printf '\\377': output isn't UTF-8"
+        );
+    }
+
+    #[test]
+    fn kills_interrupted_commands() {
+        let start = Instant::now();
+        let interrupted = || start.elapsed() > Duration::from_millis(50);
+        // The output pipes stay open in the `sleep` left behind.
+        assert_eq!(
+            run("echo a; sleep 5; echo b", "", interrupted)
+                .err()
+                .unwrap(),
+            "echo a; sleep 5; echo b: interrupted"
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn spawns_in_the_background() {
+        let (tx, rx) = mpsc::channel();
+        spawn(
+            "echo out; echo err >&2; exit 1",
+            Path::new("."),
+            move |result| tx.send(result).unwrap(),
+        );
+        let (text, status) = rx.recv().unwrap().unwrap();
+        assert_eq!(text, "err\nout\n");
+        assert_eq!(status.code(), Some(1));
+
+        let (tx, rx) = mpsc::channel();
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        spawn("pwd", &dir, move |result| tx.send(result).unwrap());
+        let (text, _) = rx.recv().unwrap().unwrap();
+        assert_eq!(text.trim_end(), dir.to_str().unwrap());
+    }
+}
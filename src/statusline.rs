@@ -0,0 +1,231 @@
+//! The status bar, drawn from two templates in which `{field}` is replaced by
+//! a piece of editor state.
+
+use std::{fs, path::Path};
+
+use kilo_rs::buffer::{Indent, LineEnding};
+
+use crate::EditorConfig;
+
+pub const DEFAULT_LEFT: &str = "{file} - {lines} lines {modified} {ro} {selection}";
+pub const DEFAULT_RIGHT: &str =
+    "{branch} {indent} {eol} {filetype} | {line}/{lines} {col}:{vcol} {percent}";
+
+/// Fields that can appear in a template.
+pub const FIELDS: &[&str] = &[
+    "file",
+    "modified",
+    "ro",
+    "lines",
+    "line",
+    "col",
+    "vcol",
+    "percent",
+    "filetype",
+    "encoding",
+    "eol",
+    "indent",
+    "selection",
+    "branch",
+];
+
+/// Checks that every `{field}` of `template` is known.
+pub fn validate(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("unclosed `{{` in {template:?}"));
+        };
+        let name = &rest[start + 1..start + len];
+        if !FIELDS.contains(&name) {
+            return Err(format!("unknown field `{{{name}}}`"));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(())
+}
+
+fn field(config: &EditorConfig, name: &str) -> String {
+    let lines = config.buffer.len();
+    match name {
+        "file" => config
+            .filename
+            .as_deref()
+            .unwrap_or("[No Name]")
+            .to_string(),
+        "modified" if config.dirty => "(modified)".to_string(),
+        "ro" if config.read_only => "[RO]".to_string(),
+        "lines" => lines.to_string(),
+        "line" => (config.cy + 1).to_string(),
+        "col" => (config.cx + 1).to_string(),
+        "vcol" => (config.rx + 1).to_string(),
+        "percent" => match config.cy {
+            0 => "Top".to_string(),
+            cy if cy + 1 >= lines => "Bot".to_string(),
+            cy => format!("{}%", (cy + 1) * 100 / lines),
+        },
+        "filetype" => match config.buffer.syntax() {
+            Some(syntax) => syntax.filetype.to_string(),
+            None => "no ft".to_string(),
+        },
+        "encoding" => "utf-8".to_string(),
+        "eol" => match config.buffer.line_ending() {
+            LineEnding::Lf => "LF".to_string(),
+            LineEnding::Crlf => "CRLF".to_string(),
+        },
+        "indent" => match config.indent {
+            Indent::None => String::new(),
+            Indent::Spaces => "spaces".to_string(),
+            Indent::Tabs => "tabs".to_string(),
+            Indent::Mixed => "[mixed indent]".to_string(),
+        },
+        "selection" => match crate::selection(config) {
+            Some((start, end)) => {
+                let count =
+                    config.buffer.char_idx(end.0, end.1) - config.buffer.char_idx(start.0, start.1);
+                format!("{count} selected")
+            }
+            None => String::new(),
+        },
+        "branch" => config.git_branch.clone().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+/// Fills in the fields of `template`. An empty field takes one of the spaces
+/// around it along, so that it doesn't leave a run of them.
+pub fn render(config: &EditorConfig, template: &str) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let value = field(config, &rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+        if value.is_empty() {
+            if out.is_empty() || out.ends_with(' ') {
+                rest = rest.strip_prefix(' ').unwrap_or(rest);
+            }
+            if rest.is_empty() && out.ends_with(' ') {
+                out.pop();
+            }
+        }
+        out.push_str(&value);
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Lays out `left` and `right` in exactly `width` columns. When they don't
+/// both fit, the right part is cut from its start to at most half the width
+/// and the left part from its end, each marked with `…`, so that they never
+/// overlap.
+pub fn layout(left: &str, right: &str, width: usize) -> String {
+    let llen = left.chars().count();
+    let rlen = right.chars().count();
+    let (left, right) = if llen + 1 + rlen <= width || (rlen == 0 && llen <= width) {
+        (left.to_string(), right.to_string())
+    } else {
+        let rmax = rlen.min(width.saturating_sub(llen + 1).max(width / 2));
+        let right = ellipsize_start(right, rmax);
+        let gap = usize::from(!right.is_empty());
+        let left = ellipsize_end(left, width.saturating_sub(right.chars().count() + gap));
+        (left, right)
+    };
+    let pad = width - left.chars().count() - right.chars().count();
+    format!("{left}{}{right}", " ".repeat(pad))
+}
+
+fn ellipsize_end(s: &str, max: usize) -> String {
+    match max {
+        _ if s.chars().count() <= max => s.to_string(),
+        0 => String::new(),
+        _ => s.chars().take(max - 1).chain(Some('…')).collect(),
+    }
+}
+
+fn ellipsize_start(s: &str, max: usize) -> String {
+    let len = s.chars().count();
+    match max {
+        _ if len <= max => s.to_string(),
+        0 => String::new(),
+        _ => Some('…')
+            .into_iter()
+            .chain(s.chars().skip(len - max + 1))
+            .collect(),
+    }
+}
+
+/// Branch checked out in the git repository containing `file`, or the short
+/// hash of the commit if the head is detached.
+pub fn git_branch(file: &Path) -> Option<String> {
+    let file = fs::canonicalize(file).ok()?;
+    for dir in file.ancestors().skip(1) {
+        let dot_git = dir.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            // A worktree or submodule, whose `.git` points at the real one.
+            let text = fs::read_to_string(&dot_git).ok()?;
+            dir.join(text.strip_prefix("gitdir:")?.trim())
+        } else {
+            continue;
+        };
+        let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+        let head = head.trim();
+        return Some(match head.strip_prefix("ref: ") {
+            Some(r) => r.strip_prefix("refs/heads/").unwrap_or(r).to_string(),
+            None => head.chars().take(7).collect(),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::Settings;
+
+    #[test]
+    fn empty_fields_take_a_space_along() {
+        let mut config = EditorConfig::new(Settings::default()).unwrap();
+        config.filename = Some("my  notes.txt".to_string());
+        let template = "{file} {modified} {ro} {selection}";
+        assert_eq!(render(&config, template), "my  notes.txt");
+        config.dirty = true;
+        assert_eq!(render(&config, template), "my  notes.txt (modified)");
+        config.read_only = true;
+        assert_eq!(render(&config, template), "my  notes.txt (modified) [RO]");
+        assert_eq!(render(&config, "{branch} {filetype} | {line}"), "no ft | 1");
+        assert_eq!(render(&config, "[{branch}]"), "[]");
+    }
+
+    #[test]
+    fn lays_out_both_parts_when_they_fit() {
+        assert_eq!(layout("abc", "xyz", 10), "abc    xyz");
+        assert_eq!(layout("abc", "xyz", 7), "abc xyz");
+        assert_eq!(layout("abc", "", 3), "abc");
+    }
+
+    #[test]
+    fn cuts_both_parts_when_narrow() {
+        assert_eq!(layout("left side", "right", 10), "lef… right");
+        assert_eq!(layout("left side", "the right part", 10), "lef… …part");
+        assert_eq!(layout("left", "right", 3), "… …");
+        assert_eq!(layout("left", "right", 0), "");
+        let (left, right) = ("src/main.rs (modified)", "rust | 10/200 5:5 Top");
+        for width in 0..=left.len() + right.len() {
+            let line = layout(left, right, width);
+            assert_eq!(line.chars().count(), width, "{line:?}");
+            if width >= 3 {
+                // Both parts show, with a space between them.
+                assert!(line.starts_with(['s', '…']), "{line:?}");
+                assert!(line.ends_with(['p', '…']), "{line:?}");
+                assert!(line.contains(' '), "{line:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+
+use crate::buffer::Row;
+
+pub const HL_HIGHLIGHT_NUMBERS: u32 = 1 << 0;
+pub const HL_HIGHLIGHT_STRINGS: u32 = 1 << 1;
+/// `'x'` char literals, told apart from `'a` lifetimes.
+pub const HL_HIGHLIGHT_CHARS: u32 = 1 << 2;
+/// `b"..."` byte strings, `b'x'` byte chars and `r#"..."#` raw strings.
+pub const HL_HIGHLIGHT_PREFIXED_STRINGS: u32 = 1 << 3;
+/// `#[...]` and `#![...]` attributes.
+pub const HL_HIGHLIGHT_ATTRIBUTES: u32 = 1 << 4;
+/// `name!` macro invocations.
+pub const HL_HIGHLIGHT_MACROS: u32 = 1 << 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Highlight {
+    Normal,
+    Number,
+    String,
+    Comment,
+    Keyword1,
+    Keyword2,
+    Type,
+    Function,
+    Macro,
+    Lifetime,
+    Attribute,
+    Constant,
+    Match,
+    /// The bracket matching the one under the cursor.
+    Bracket,
+}
+
+impl Highlight {
+    pub const ALL: [Highlight; 14] = [
+        Self::Normal,
+        Self::Number,
+        Self::String,
+        Self::Comment,
+        Self::Keyword1,
+        Self::Keyword2,
+        Self::Type,
+        Self::Function,
+        Self::Macro,
+        Self::Lifetime,
+        Self::Attribute,
+        Self::Constant,
+        Self::Match,
+        Self::Bracket,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Number => "number",
+            Self::String => "string",
+            Self::Comment => "comment",
+            Self::Keyword1 => "keyword1",
+            Self::Keyword2 => "keyword2",
+            Self::Type => "type",
+            Self::Function => "function",
+            Self::Macro => "macro",
+            Self::Lifetime => "lifetime",
+            Self::Attribute => "attribute",
+            Self::Constant => "constant",
+            Self::Match => "match",
+            Self::Bracket => "bracket",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Syntax {
+    pub filetype: &'static str,
+    /// File extensions or whole file names.
+    pub filematch: &'static [&'static str],
+    pub keywords: &'static [&'static str],
+    pub single_line_comment_start: Option<&'static str>,
+    pub flags: u32,
+    /// Opening and closing chars inserted together when `auto_pairs` is on.
+    pub pairs: &'static [(char, char)],
+}
+
+/// Pairs used for files without a file type.
+pub const DEFAULT_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+pub const HLDB: [Syntax; 2] = [
+    Syntax {
+        filetype: "rust",
+        filematch: &["rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+            "mut", "pub", "ref", "return", "self", "static", "struct", "trait", "type", "unsafe",
+            "use", "Vec", "Option", "vec", "where", "while", "i8|", "i16|", "i32|", "i64|",
+            "i128|", "isize|", "u8|", "u16|", "u32|", "u64|", "u128|", "usize|", "f32|", "f64|",
+            "char|", "bool|", "&str|", "str|", "()|", "String|",
+        ],
+        single_line_comment_start: Some("//"),
+        flags: HL_HIGHLIGHT_NUMBERS
+            | HL_HIGHLIGHT_STRINGS
+            | HL_HIGHLIGHT_CHARS
+            | HL_HIGHLIGHT_PREFIXED_STRINGS
+            | HL_HIGHLIGHT_ATTRIBUTES
+            | HL_HIGHLIGHT_MACROS,
+        // No `'`, which starts lifetimes at least as often as char literals.
+        pairs: &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')],
+    },
+    Syntax {
+        filetype: "gitcommit",
+        filematch: &["COMMIT_EDITMSG", "MERGE_MSG", "TAG_EDITMSG"],
+        keywords: &[],
+        // Git only treats `#` as a comment at the start of a line.
+        single_line_comment_start: None,
+        flags: 0,
+        pairs: DEFAULT_PAIRS,
+    },
+];
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || c == '\0' || ",.()+-/*=~%<>[];".contains(c)
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Length of the identifier starting at `i`.
+fn ident_len(chars: &[char], i: usize) -> usize {
+    chars[i..].iter().take_while(|&&c| is_ident(c)).count()
+}
+
+/// Length of the number literal starting at `i`: digits with `_`, a `0x`,
+/// `0o` or `0b` prefix, a fraction, an exponent and a type suffix.
+fn number_len(chars: &[char], i: usize) -> usize {
+    let radix_prefix = chars[i] == '0' && matches!(chars.get(i + 1), Some('x' | 'o' | 'b'));
+    let mut j = if radix_prefix { i + 2 } else { i };
+    while let Some(&c) = chars.get(j) {
+        let next = chars.get(j + 1).copied();
+        let accept = match c {
+            '.' => !radix_prefix && next.is_some_and(|n| n.is_ascii_digit()),
+            '+' | '-' => !radix_prefix && matches!(chars[j - 1], 'e' | 'E'),
+            c => is_ident(c),
+        };
+        if !accept {
+            break;
+        }
+        j += 1;
+    }
+    j - i
+}
+
+/// Length of the char literal starting with the `'` at `i`, or `None` if it
+/// isn't one (e.g. it starts a lifetime).
+fn char_len(chars: &[char], i: usize) -> Option<usize> {
+    if chars.get(i) != Some(&'\'') {
+        return None;
+    }
+    match chars.get(i + 1)? {
+        '\\' => (i + 3..chars.len().min(i + 12))
+            .find(|&j| chars[j] == '\'')
+            .map(|j| j + 1 - i),
+        '\'' => None,
+        _ => (chars.get(i + 2) == Some(&'\'')).then_some(3),
+    }
+}
+
+/// Length of the raw string starting with the `r` at `i`, running to the end
+/// of the row if it isn't closed, or `None` if there is no raw string there.
+fn raw_string_len(chars: &[char], i: usize) -> Option<usize> {
+    let hashes = chars[i + 1..].iter().take_while(|&&c| c == '#').count();
+    let start = i + 1 + hashes;
+    if chars.get(start) != Some(&'"') {
+        return None;
+    }
+    let end = (start + 1..chars.len())
+        .find(|&j| {
+            chars[j] == '"'
+                && chars.len() > j + hashes
+                && chars[j + 1..=j + hashes].iter().all(|&c| c == '#')
+        })
+        .map_or(chars.len(), |j| j + 1 + hashes);
+    Some(end - i)
+}
+
+/// Length of the attribute starting with the `#` at `i`, up to its closing
+/// bracket or the end of the row, or `None` if there is no attribute there.
+fn attribute_len(chars: &[char], i: usize) -> Option<usize> {
+    let open = match chars.get(i + 1)? {
+        '[' => i + 1,
+        '!' if chars.get(i + 2) == Some(&'[') => i + 2,
+        _ => return None,
+    };
+    let mut depth = 0;
+    for (j, &c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(j + 1 - i);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(chars.len() - i)
+}
+
+/// Highlights rows for one `Syntax`, with its keywords indexed up front so
+/// each row is highlighted in a single pass.
+pub struct Highlighter {
+    pub syntax: Syntax,
+    keywords: HashMap<&'static str, Highlight>,
+    /// Distinct keyword lengths in chars, longest first.
+    keyword_lens: Vec<usize>,
+}
+
+impl Highlighter {
+    pub fn new(syntax: Syntax) -> Self {
+        let mut keywords = HashMap::new();
+        for kw in syntax.keywords {
+            match kw.strip_suffix('|') {
+                Some(kw) => keywords.insert(kw, Highlight::Keyword2),
+                None => keywords.insert(kw, Highlight::Keyword1),
+            };
+        }
+        let mut keyword_lens: Vec<usize> = keywords.keys().map(|kw| kw.chars().count()).collect();
+        keyword_lens.sort_unstable_by(|a, b| b.cmp(a));
+        keyword_lens.dedup();
+        Highlighter {
+            syntax,
+            keywords,
+            keyword_lens,
+        }
+    }
+
+    pub fn highlight(&self, row: &mut Row) {
+        row.hl.clear();
+        row.hl.resize(row.rsize, Highlight::Normal);
+
+        let render = row.render.as_str();
+        // Byte offset of every char, plus the end of the string, so that
+        // char positions can be sliced without rescanning the row.
+        let offsets: Vec<usize> = render
+            .char_indices()
+            .map(|(b, _)| b)
+            .chain(std::iter::once(render.len()))
+            .collect();
+        let chars: Vec<char> = render.chars().collect();
+        let len = chars.len();
+
+        let flags = self.syntax.flags;
+        let scs = self.syntax.single_line_comment_start;
+        let mut prev_sep = true;
+        let mut in_string = false;
+
+        let mut i = 0;
+        while i < len {
+            let c = chars[i];
+
+            if let Some(s) = scs {
+                if !in_string && render[offsets[i]..].starts_with(s) {
+                    row.hl[i..].fill(Highlight::Comment);
+                    break;
+                }
+            }
+
+            if flags & HL_HIGHLIGHT_STRINGS != 0 {
+                if in_string {
+                    row.hl[i] = Highlight::String;
+
+                    if c == '\\' && i + 1 < len {
+                        row.hl[i + 1] = Highlight::String;
+                        i += 2;
+                        continue;
+                    }
+
+                    if c == '"' {
+                        in_string = false;
+                    }
+                    i += 1;
+                    prev_sep = true;
+                    continue;
+                } else if c == '"' {
+                    in_string = true;
+                    row.hl[i] = Highlight::String;
+                    i += 1;
+                    continue;
+                }
+            }
+
+            if flags & HL_HIGHLIGHT_PREFIXED_STRINGS != 0 && prev_sep && (c == 'b' || c == 'r') {
+                let r = if c == 'b' { i + 1 } else { i };
+                if chars.get(r) == Some(&'r') {
+                    if let Some(n) = raw_string_len(&chars, r) {
+                        row.hl[i..r + n].fill(Highlight::String);
+                        i = r + n;
+                        continue;
+                    }
+                } else if chars.get(i + 1) == Some(&'"') {
+                    row.hl[i..i + 2].fill(Highlight::String);
+                    in_string = true;
+                    i += 2;
+                    continue;
+                } else if let Some(n) = char_len(&chars, i + 1) {
+                    row.hl[i..i + 1 + n].fill(Highlight::String);
+                    i += 1 + n;
+                    continue;
+                }
+            }
+
+            if flags & HL_HIGHLIGHT_CHARS != 0 && c == '\'' {
+                if let Some(n) = char_len(&chars, i) {
+                    row.hl[i..i + n].fill(Highlight::String);
+                    i += n;
+                    prev_sep = true;
+                    continue;
+                }
+                let n = ident_len(&chars, i + 1);
+                if n > 0 {
+                    row.hl[i..i + 1 + n].fill(Highlight::Lifetime);
+                    i += 1 + n;
+                    prev_sep = false;
+                    continue;
+                }
+            }
+
+            if flags & HL_HIGHLIGHT_ATTRIBUTES != 0 && prev_sep && c == '#' {
+                if let Some(n) = attribute_len(&chars, i) {
+                    row.hl[i..i + n].fill(Highlight::Attribute);
+                    i += n;
+                    continue;
+                }
+            }
+
+            if flags & HL_HIGHLIGHT_NUMBERS != 0 && prev_sep && c.is_ascii_digit() {
+                let n = number_len(&chars, i);
+                row.hl[i..i + n].fill(Highlight::Number);
+                i += n;
+                prev_sep = false;
+                continue;
+            }
+
+            if flags & HL_HIGHLIGHT_MACROS != 0 && prev_sep && is_ident(c) {
+                let n = ident_len(&chars, i);
+                if chars.get(i + n) == Some(&'!') && chars.get(i + n + 1) != Some(&'=') {
+                    row.hl[i..i + n + 1].fill(Highlight::Macro);
+                    i += n + 1;
+                    continue;
+                }
+            }
+
+            if prev_sep {
+                let keyword = self.keyword_lens.iter().find_map(|&klen| {
+                    let end = i + klen;
+                    if end > len || !chars.get(end).is_none_or(|&c| is_separator(c)) {
+                        return None;
+                    }
+                    let hl = self.keywords.get(&render[offsets[i]..offsets[end]])?;
+                    Some((klen, *hl))
+                });
+                if let Some((klen, hl)) = keyword {
+                    row.hl[i..i + klen].fill(hl);
+                    i += klen;
+                    prev_sep = false;
+                    continue;
+                }
+            }
+
+            prev_sep = is_separator(c);
+            i += 1;
+        }
+    }
+}
+
+/// Fills in `row.hl`, leaving everything `Normal` when there is no
+/// highlighter for the file type.
+pub fn update_syntax(highlighter: Option<&Highlighter>, row: &mut Row) {
+    match highlighter {
+        Some(h) => h.highlight(row),
+        None => {
+            row.hl.clear();
+            row.hl.resize(row.rsize, Highlight::Normal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Highlights `line` as Rust and spells the result one letter per char:
+    /// `.` normal, `n` number, `s` string, `c` comment, `k` keyword1,
+    /// `t` keyword2, `l` lifetime, `a` attribute, `m` macro.
+    fn highlight(line: &str) -> String {
+        let mut row = Row {
+            render: line.to_string(),
+            rsize: line.chars().count(),
+            hl: Vec::new(),
+        };
+        Highlighter::new(HLDB[0]).highlight(&mut row);
+        row.hl
+            .iter()
+            .map(|hl| match hl {
+                Highlight::Number => 'n',
+                Highlight::String => 's',
+                Highlight::Comment => 'c',
+                Highlight::Keyword1 => 'k',
+                Highlight::Keyword2 => 't',
+                Highlight::Lifetime => 'l',
+                Highlight::Attribute => 'a',
+                Highlight::Macro => 'm',
+                _ => '.',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keywords_need_separators_on_both_sides() {
+        assert_eq!(highlight("let x = letter;"), "kkk............");
+        assert_eq!(highlight("fn f(x: u8)"), "kk......tt.");
+    }
+
+    #[test]
+    fn keyword_at_end_of_line() {
+        assert_eq!(highlight("x as"), "..kk");
+        assert_eq!(highlight("mut"), "kkk");
+        assert_eq!(highlight("-> usize"), "...ttttt");
+    }
+
+    #[test]
+    fn keyword_containing_separators() {
+        assert_eq!(highlight("f() -> ()"), ".......tt");
+        assert_eq!(highlight("s: &str"), "...tttt");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(highlight("x = 3.14;"), "....nnnn.");
+        assert_eq!(highlight("x2 = 2"), ".....n");
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        assert_eq!(highlight(r#"s = "a\"b" + 1"#), r#"....ssssss...n"#);
+        assert_eq!(highlight(r#""unterminated"#), "sssssssssssss");
+        assert_eq!(highlight(r#""\"#), "ss");
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(highlight("let // fn"), "kkk.ccccc");
+        assert_eq!(highlight(r#""//" // x"#), "ssss.cccc");
+    }
+
+    #[test]
+    fn multibyte_chars() {
+        assert_eq!(highlight("é let ü"), "..kkk..");
+        assert_eq!(highlight("\"ü\" 1 // ñ"), "sss.n.cccc");
+    }
+
+    #[test]
+    fn chars_and_lifetimes() {
+        assert_eq!(highlight("'a'"), "sss");
+        assert_eq!(highlight(r"'\n' '\'' '\\'"), "ssss.ssss.ssss");
+        assert_eq!(highlight(r"'\u{1F600}'"), "sssssssssss");
+        assert_eq!(highlight("fn f<'a>(x: &'a str)"), "kk...ll......ll.ttt.");
+        assert_eq!(highlight("&'static str"), ".lllllll.ttt");
+        assert_eq!(highlight("'é' 'ü"), "sss.ll");
+        assert_eq!(highlight("x' 1"), "...n");
+    }
+
+    #[test]
+    fn byte_and_raw_strings() {
+        assert_eq!(highlight(r#"b"a\"b" 1"#), "sssssss.n");
+        assert_eq!(highlight("b'x' br"), "ssss...");
+        assert_eq!(highlight(r##"r#"a "quoted" b"# 1"##), "sssssssssssssssss.n");
+        assert_eq!(highlight(r#"br"\" 1"#), "sssss.n");
+        assert_eq!(highlight(r##"r#"open"##), "sssssss");
+        assert_eq!(highlight("r#type ref"), ".......kkk");
+        assert_eq!(highlight("bar(b)"), "......");
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(highlight("#[derive(Debug)]"), "aaaaaaaaaaaaaaaa");
+        assert_eq!(highlight("#![allow(x)] fn"), "aaaaaaaaaaaa.kk");
+        assert_eq!(highlight("#[cfg(all(a, b))] 1"), "aaaaaaaaaaaaaaaaa.n");
+        assert_eq!(highlight("#[doc"), "aaaaa");
+        assert_eq!(highlight("x#[y]"), ".....");
+    }
+
+    #[test]
+    fn macros() {
+        assert_eq!(highlight("println!(\"{}\", x);"), "mmmmmmmm.ssss.....");
+        assert_eq!(highlight("vec![1]"), "mmmm.n.");
+        assert_eq!(highlight("a != b"), "......");
+        assert_eq!(highlight("x!=y"), "....");
+        assert_eq!(highlight("!done"), ".....");
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(highlight("0xFF_u8"), "nnnnnnn");
+        assert_eq!(highlight("1e-3 + 2.5E+10f64"), "nnnn...nnnnnnnnnn");
+        assert_eq!(highlight("0b1010 0o17"), "nnnnnn.nnnn");
+        assert_eq!(highlight("1_000_000usize"), "nnnnnnnnnnnnnn");
+        assert_eq!(highlight("0..10"), "n..nn");
+        assert_eq!(highlight("1.max(2)"), "n.....n.");
+        assert_eq!(highlight("t.0 x1 1x"), "..n....nn");
+        assert_eq!(highlight("0xE-1"), "nnn.n");
+    }
+
+    #[test]
+    fn long_line_is_highlighted_fully() {
+        let line = "let x = 1; ".repeat(10_000);
+        let hl = highlight(&line);
+        assert_eq!(hl.len(), line.len());
+        assert_eq!(&hl[hl.len() - 11..], "kkk.....n..");
+    }
+}
@@ -0,0 +1,628 @@
+use std::{collections::HashMap, env, fmt::Write};
+
+use toml::{Table, Value};
+
+use kilo_rs::syntax::Highlight;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Color {
+    Default,
+    /// One of the 16 basic terminal colors: 0-7 normal, 8-15 bright.
+    Ansi(u8),
+    /// An entry of the 256-color palette.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// How many colors the terminal can show. Colors a theme asks for are
+/// downgraded to the nearest one the terminal supports.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum ColorDepth {
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    pub fn detect() -> Self {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return Self::TrueColor;
+        }
+        let term = env::var("TERM").unwrap_or_default();
+        if term.contains("256color") {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::detect()),
+            "16" => Some(Self::Ansi16),
+            "256" => Some(Self::Ansi256),
+            "truecolor" | "24bit" => Some(Self::TrueColor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub italic: bool,
+    pub reverse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            fg: Color::Default,
+            bg: Color::Default,
+            bold: false,
+            italic: false,
+            reverse: false,
+        }
+    }
+}
+
+impl Style {
+    const fn fg(fg: Color) -> Self {
+        Style {
+            fg,
+            bg: Color::Default,
+            bold: false,
+            italic: false,
+            reverse: false,
+        }
+    }
+
+    const fn on(mut self, bg: Color) -> Self {
+        self.bg = bg;
+        self
+    }
+
+    const fn bold(mut self) -> Self {
+        self.bold = true;
+        self
+    }
+
+    const fn italic(mut self) -> Self {
+        self.italic = true;
+        self
+    }
+
+    /// SGR sequence that resets all attributes and then applies this style.
+    pub fn escape(&self, depth: ColorDepth) -> String {
+        let mut s = String::from("\x1b[0");
+        if self.bold {
+            s.push_str(";1");
+        }
+        if self.italic {
+            s.push_str(";3");
+        }
+        if self.reverse {
+            s.push_str(";7");
+        }
+        push_color(&mut s, self.fg, depth, false);
+        push_color(&mut s, self.bg, depth, true);
+        s.push('m');
+        s
+    }
+}
+
+fn push_color(s: &mut String, color: Color, depth: ColorDepth, bg: bool) {
+    let color = match (color, depth) {
+        (Color::Rgb(r, g, b), ColorDepth::Ansi256) => Color::Indexed(rgb_to_256(r, g, b)),
+        (Color::Rgb(r, g, b), ColorDepth::Ansi16) => Color::Ansi(rgb_to_16(r, g, b)),
+        (Color::Indexed(n), ColorDepth::Ansi16) => {
+            let (r, g, b) = indexed_to_rgb(n);
+            Color::Ansi(rgb_to_16(r, g, b))
+        }
+        (color, _) => color,
+    };
+    let base = if bg { 40 } else { 30 };
+    let _ = match color {
+        Color::Default => write!(s, ";{}", base + 9),
+        Color::Ansi(n) if n < 8 => write!(s, ";{}", base + n),
+        Color::Ansi(n) => write!(s, ";{}", base + 60 + (n - 8)),
+        Color::Indexed(n) => write!(s, ";{};5;{}", base + 8, n),
+        Color::Rgb(r, g, b) => write!(s, ";{};2;{};{};{}", base + 8, r, g, b),
+    };
+}
+
+/// The xterm defaults for the 16 basic colors.
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+fn indexed_to_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_RGB[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            )
+        }
+        _ => {
+            let v = 8 + (n - 232) * 10;
+            (v, v, v)
+        }
+    }
+}
+
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs())
+            .unwrap() as u8
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let avg = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+    let gray = 232 + (avg.saturating_sub(3) / 10).min(23);
+    if distance((r, g, b), indexed_to_rgb(gray)) < distance((r, g, b), indexed_to_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn rgb_to_16(r: u8, g: u8, b: u8) -> u8 {
+    (0..16)
+        .min_by_key(|&i| distance((r, g, b), ANSI_RGB[i as usize]))
+        .unwrap()
+}
+
+#[derive(Clone)]
+pub struct Theme {
+    pub name: String,
+    pub highlight: HashMap<Highlight, Style>,
+    pub statusbar: Style,
+    pub gutter: Style,
+    pub selection: Style,
+    /// Glyphs drawn for tabs when showing whitespace.
+    pub whitespace: Style,
+    /// Glyphs drawn for trailing and non-breaking spaces.
+    pub trailing_whitespace: Style,
+    /// Only the background is used, so text crossing a ruler keeps its colors.
+    pub ruler: Style,
+    /// Gutter signs of rows with errors, and with warnings or notes.
+    pub error: Style,
+    pub warning: Style,
+}
+
+impl Theme {
+    pub fn style(&self, hl: Highlight) -> Style {
+        self.highlight.get(&hl).copied().unwrap_or_default()
+    }
+
+    fn style_mut(&mut self, element: &str) -> Option<&mut Style> {
+        match element {
+            "statusbar" => Some(&mut self.statusbar),
+            "gutter" => Some(&mut self.gutter),
+            "selection" => Some(&mut self.selection),
+            "whitespace" => Some(&mut self.whitespace),
+            "trailing_whitespace" => Some(&mut self.trailing_whitespace),
+            "ruler" => Some(&mut self.ruler),
+            "error" => Some(&mut self.error),
+            "warning" => Some(&mut self.warning),
+            _ => {
+                let hl = Highlight::ALL.iter().find(|hl| hl.name() == element)?;
+                Some(self.highlight.entry(*hl).or_default())
+            }
+        }
+    }
+}
+
+const BUILTIN: [&str; 4] = ["default", "solarized-dark", "gruvbox", "mono"];
+
+fn theme(
+    name: &str,
+    styles: &[(Highlight, Style)],
+    statusbar: Style,
+    // Line numbers, then error and warning signs.
+    gutter: (Style, Style, Style),
+    selection: Style,
+    whitespace: (Style, Style),
+    ruler: Style,
+) -> Theme {
+    Theme {
+        name: name.to_string(),
+        highlight: styles.iter().copied().collect(),
+        statusbar,
+        gutter: gutter.0,
+        selection,
+        whitespace: whitespace.0,
+        trailing_whitespace: whitespace.1,
+        ruler,
+        error: gutter.1,
+        warning: gutter.2,
+    }
+}
+
+pub fn builtin(name: &str) -> Option<Theme> {
+    use Color::*;
+    use Highlight as H;
+
+    let reverse = Style {
+        reverse: true,
+        ..Style::default()
+    };
+    let theme = match name {
+        "default" => theme(
+            name,
+            &[
+                (H::Number, Style::fg(Ansi(1))),
+                (H::String, Style::fg(Ansi(5))),
+                (H::Comment, Style::fg(Ansi(6))),
+                (H::Keyword1, Style::fg(Ansi(3))),
+                (H::Keyword2, Style::fg(Ansi(2))),
+                (H::Type, Style::fg(Ansi(2))),
+                (H::Function, Style::fg(Ansi(12))),
+                (H::Macro, Style::fg(Ansi(13))),
+                (H::Lifetime, Style::fg(Ansi(11))),
+                (H::Attribute, Style::fg(Ansi(14))),
+                (H::Constant, Style::fg(Ansi(1))),
+                (H::Match, Style::fg(Ansi(4))),
+                (H::Bracket, reverse),
+            ],
+            reverse,
+            (
+                Style::fg(Ansi(8)),
+                Style::fg(Ansi(9)).bold(),
+                Style::fg(Ansi(11)),
+            ),
+            reverse,
+            (Style::fg(Ansi(8)), Style::fg(Ansi(9))),
+            Style::default().on(Ansi(8)),
+        ),
+        "solarized-dark" => {
+            let bg = Rgb(0x00, 0x2b, 0x36);
+            let bg_hl = Rgb(0x07, 0x36, 0x42);
+            theme(
+                name,
+                &[
+                    (H::Normal, Style::fg(Rgb(0x83, 0x94, 0x96)).on(bg)),
+                    (H::Number, Style::fg(Rgb(0xd3, 0x36, 0x82)).on(bg)),
+                    (H::String, Style::fg(Rgb(0x2a, 0xa1, 0x98)).on(bg)),
+                    (H::Comment, Style::fg(Rgb(0x58, 0x6e, 0x75)).on(bg).italic()),
+                    (H::Keyword1, Style::fg(Rgb(0x85, 0x99, 0x00)).on(bg)),
+                    (H::Keyword2, Style::fg(Rgb(0xb5, 0x89, 0x00)).on(bg)),
+                    (H::Type, Style::fg(Rgb(0xb5, 0x89, 0x00)).on(bg)),
+                    (H::Function, Style::fg(Rgb(0x26, 0x8b, 0xd2)).on(bg)),
+                    (H::Macro, Style::fg(Rgb(0x6c, 0x71, 0xc4)).on(bg)),
+                    (H::Lifetime, Style::fg(Rgb(0xcb, 0x4b, 0x16)).on(bg)),
+                    (H::Attribute, Style::fg(Rgb(0x93, 0xa1, 0xa1)).on(bg)),
+                    (H::Constant, Style::fg(Rgb(0xd3, 0x36, 0x82)).on(bg)),
+                    (H::Match, Style::fg(bg).on(Rgb(0xcb, 0x4b, 0x16))),
+                    (
+                        H::Bracket,
+                        Style::fg(Rgb(0xdc, 0x32, 0x2f)).on(bg_hl).bold(),
+                    ),
+                ],
+                Style::fg(Rgb(0x93, 0xa1, 0xa1)).on(bg_hl),
+                (
+                    Style::fg(Rgb(0x58, 0x6e, 0x75)).on(bg),
+                    Style::fg(Rgb(0xdc, 0x32, 0x2f)).on(bg).bold(),
+                    Style::fg(Rgb(0xb5, 0x89, 0x00)).on(bg),
+                ),
+                Style::fg(Rgb(0x93, 0xa1, 0xa1)).on(bg_hl),
+                (
+                    Style::fg(Rgb(0x07, 0x36, 0x42)).on(bg),
+                    Style::fg(Rgb(0xdc, 0x32, 0x2f)).on(bg),
+                ),
+                Style::default().on(bg_hl),
+            )
+        }
+        "gruvbox" => {
+            let bg = Rgb(0x28, 0x28, 0x28);
+            theme(
+                name,
+                &[
+                    (H::Normal, Style::fg(Rgb(0xeb, 0xdb, 0xb2)).on(bg)),
+                    (H::Number, Style::fg(Rgb(0xd3, 0x86, 0x9b)).on(bg)),
+                    (H::String, Style::fg(Rgb(0xb8, 0xbb, 0x26)).on(bg)),
+                    (H::Comment, Style::fg(Rgb(0x92, 0x83, 0x74)).on(bg).italic()),
+                    (H::Keyword1, Style::fg(Rgb(0xfb, 0x49, 0x34)).on(bg).bold()),
+                    (H::Keyword2, Style::fg(Rgb(0xfa, 0xbd, 0x2f)).on(bg)),
+                    (H::Type, Style::fg(Rgb(0xfa, 0xbd, 0x2f)).on(bg)),
+                    (H::Function, Style::fg(Rgb(0xb8, 0xbb, 0x26)).on(bg).bold()),
+                    (H::Macro, Style::fg(Rgb(0x8e, 0xc0, 0x7c)).on(bg)),
+                    (H::Lifetime, Style::fg(Rgb(0xfe, 0x80, 0x19)).on(bg)),
+                    (H::Attribute, Style::fg(Rgb(0x83, 0xa5, 0x98)).on(bg)),
+                    (H::Constant, Style::fg(Rgb(0xd3, 0x86, 0x9b)).on(bg)),
+                    (H::Match, Style::fg(bg).on(Rgb(0xfe, 0x80, 0x19))),
+                    (
+                        H::Bracket,
+                        Style::fg(Rgb(0xfe, 0x80, 0x19))
+                            .on(Rgb(0x50, 0x49, 0x45))
+                            .bold(),
+                    ),
+                ],
+                Style::fg(Rgb(0xeb, 0xdb, 0xb2)).on(Rgb(0x50, 0x49, 0x45)),
+                (
+                    Style::fg(Rgb(0x7c, 0x6f, 0x64)).on(bg),
+                    Style::fg(Rgb(0xfb, 0x49, 0x34)).on(bg).bold(),
+                    Style::fg(Rgb(0xfa, 0xbd, 0x2f)).on(bg),
+                ),
+                Style::fg(Rgb(0xeb, 0xdb, 0xb2)).on(Rgb(0x66, 0x5c, 0x54)),
+                (
+                    Style::fg(Rgb(0x50, 0x49, 0x45)).on(bg),
+                    Style::fg(Rgb(0xfb, 0x49, 0x34)).on(bg),
+                ),
+                Style::default().on(Rgb(0x3c, 0x38, 0x36)),
+            )
+        }
+        "mono" => theme(
+            name,
+            &[
+                (H::Comment, Style::default().italic()),
+                (H::Keyword1, Style::default().bold()),
+                (H::Macro, Style::default().bold()),
+                (H::Match, reverse),
+                (H::Bracket, reverse),
+            ],
+            reverse,
+            (Style::default(), reverse, Style::default().bold()),
+            reverse,
+            (Style::default(), reverse),
+            Style::default().on(Ansi(8)),
+        ),
+        _ => return None,
+    };
+    Some(theme)
+}
+
+pub fn builtin_names() -> &'static [&'static str] {
+    &BUILTIN
+}
+
+/// Parses a color written as a name (`red`, `bright-blue`, `default`), a
+/// `#rrggbb` hex triplet or a 256-color palette index.
+fn parse_color(value: &Value) -> Option<Color> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    match value {
+        Value::Integer(n @ 0..=255) => Some(Color::Indexed(*n as u8)),
+        Value::String(s) if s == "default" => Some(Color::Default),
+        Value::String(s) if s.starts_with('#') => {
+            // Checked first so that slicing can't split a multibyte char.
+            if s.len() != 7 || !s[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+            Some(Color::Rgb(channel(1)?, channel(3)?, channel(5)?))
+        }
+        Value::String(s) => {
+            let (bright, name) = match s.strip_prefix("bright-") {
+                Some(name) => (8, name),
+                None => (0, s.as_str()),
+            };
+            let i = NAMES.iter().position(|n| *n == name)?;
+            Some(Color::Ansi(bright + i as u8))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a style: either a bare foreground color or a table with `fg`, `bg`,
+/// `bold`, `italic` and `reverse` keys.
+fn parse_style(value: &Value) -> Result<Style, String> {
+    let Some(table) = value.as_table() else {
+        return parse_color(value)
+            .map(Style::fg)
+            .ok_or_else(|| format!("invalid color {value}"));
+    };
+    let mut style = Style::default();
+    for (key, v) in table {
+        match key.as_str() {
+            "fg" | "bg" => {
+                let color = parse_color(v).ok_or_else(|| format!("invalid color {v}"))?;
+                if key == "fg" {
+                    style.fg = color;
+                } else {
+                    style.bg = color;
+                }
+            }
+            "bold" | "italic" | "reverse" => {
+                let flag = v
+                    .as_bool()
+                    .ok_or_else(|| format!("{key}: expected true or false, got {v}"))?;
+                match key.as_str() {
+                    "bold" => style.bold = flag,
+                    "italic" => style.italic = flag,
+                    _ => style.reverse = flag,
+                }
+            }
+            _ => return Err(format!("unknown style attribute `{key}`")),
+        }
+    }
+    Ok(style)
+}
+
+/// Builds the user themes of a `[themes.NAME]` table. Each theme starts from
+/// the builtin named by its `inherits` key, or `default`.
+pub fn parse_themes(table: &Table, errors: &mut Vec<String>) -> Vec<Theme> {
+    let mut themes = Vec::new();
+    for (name, value) in table {
+        let Some(elements) = value.as_table() else {
+            errors.push(format!("themes.{name}: expected a table"));
+            continue;
+        };
+        let base = elements
+            .get("inherits")
+            .and_then(|v| v.as_str())
+            .unwrap_or("default");
+        let Some(mut theme) = builtin(base) else {
+            errors.push(format!("themes.{name}: unknown theme to inherit `{base}`"));
+            continue;
+        };
+        theme.name = name.clone();
+        for (element, value) in elements {
+            if element == "inherits" {
+                continue;
+            }
+            let Some(style) = theme.style_mut(element) else {
+                errors.push(format!("themes.{name}: unknown element `{element}`"));
+                continue;
+            };
+            match parse_style(value) {
+                Ok(s) => *style = s,
+                Err(e) => errors.push(format!("themes.{name}.{element}: {e}")),
+            }
+        }
+        themes.push(theme);
+    }
+    themes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(s: &str) -> Option<Color> {
+        parse_color(&Value::String(s.to_string()))
+    }
+
+    #[test]
+    fn maps_rgb_onto_the_palettes() {
+        assert_eq!(rgb_to_256(0, 0, 0), 16);
+        assert_eq!(rgb_to_256(255, 255, 255), 231);
+        assert_eq!(rgb_to_256(255, 0, 0), 196);
+        assert_eq!(rgb_to_256(0x5f, 0x87, 0xaf), 67);
+        // Grays closer to the gray ramp than to the cube use it.
+        assert_eq!(rgb_to_256(0x80, 0x80, 0x80), 244);
+        assert_eq!(rgb_to_256(0x08, 0x08, 0x08), 232);
+
+        assert_eq!(rgb_to_16(0, 0, 0), 0);
+        assert_eq!(rgb_to_16(250, 10, 10), 9);
+        assert_eq!(rgb_to_16(200, 0, 0), 1);
+        assert_eq!(rgb_to_16(240, 240, 240), 7);
+        assert_eq!(rgb_to_16(250, 250, 250), 15);
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(color("default"), Some(Color::Default));
+        assert_eq!(color("red"), Some(Color::Ansi(1)));
+        assert_eq!(color("bright-white"), Some(Color::Ansi(15)));
+        assert_eq!(color("#0a0B0c"), Some(Color::Rgb(10, 11, 12)));
+        assert_eq!(parse_color(&Value::Integer(200)), Some(Color::Indexed(200)));
+
+        for bad in [
+            "purple", "bright-", "Red", "#12345", "#1234567", "#12345g", "#aé000", "",
+        ] {
+            assert_eq!(color(bad), None, "{bad}");
+        }
+        assert_eq!(parse_color(&Value::Integer(256)), None);
+        assert_eq!(parse_color(&Value::Integer(-1)), None);
+        assert_eq!(parse_color(&Value::Boolean(true)), None);
+    }
+
+    #[test]
+    fn parses_styles() {
+        let value: Value = "{ fg = \"red\", bg = 17, bold = true }".parse().unwrap();
+        let style = parse_style(&value).unwrap();
+        assert_eq!(
+            style,
+            Style::fg(Color::Ansi(1)).on(Color::Indexed(17)).bold()
+        );
+        assert_eq!(
+            parse_style(&Value::String("blue".to_string())),
+            Ok(Style::fg(Color::Ansi(4)))
+        );
+
+        let error = |s: &str| parse_style(&s.parse::<Value>().unwrap()).unwrap_err();
+        assert_eq!(error("\"nope\""), "invalid color \"nope\"");
+        assert_eq!(error("{ bg = \"#zzzzzz\" }"), "invalid color \"#zzzzzz\"");
+        assert_eq!(error("{ bold = 1 }"), "bold: expected true or false, got 1");
+        assert_eq!(
+            error("{ underline = true }"),
+            "unknown style attribute `underline`"
+        );
+    }
+
+    #[test]
+    fn parses_themes() {
+        let table: Table = r##"
+            [dark]
+            inherits = "mono"
+            comment = "#ff0000"
+            statusbar = { fg = "black", bg = "white" }
+            sparkle = "red"
+            string = "nope"
+
+            [broken]
+            inherits = "nope"
+        "##
+        .parse()
+        .unwrap();
+        let mut errors = Vec::new();
+        let themes = parse_themes(&table, &mut errors);
+        assert_eq!(themes.len(), 1);
+        let theme = &themes[0];
+        assert_eq!(theme.name, "dark");
+        assert_eq!(theme.style(Highlight::Comment).fg, Color::Rgb(255, 0, 0));
+        // The rest comes from the inherited theme.
+        assert!(theme.style(Highlight::Keyword1).bold);
+        assert_eq!(
+            theme.statusbar,
+            Style::fg(Color::Ansi(0)).on(Color::Ansi(7))
+        );
+        assert_eq!(
+            errors,
+            [
+                "themes.broken: unknown theme to inherit `nope`",
+                "themes.dark: unknown element `sparkle`",
+                "themes.dark.string: invalid color \"nope\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn downgrades_colors_to_the_depth() {
+        let style = Style::fg(Color::Rgb(255, 0, 0)).on(Color::Indexed(21));
+        assert_eq!(
+            style.escape(ColorDepth::TrueColor),
+            "\x1b[0;38;2;255;0;0;48;5;21m"
+        );
+        assert_eq!(
+            style.escape(ColorDepth::Ansi256),
+            "\x1b[0;38;5;196;48;5;21m"
+        );
+        assert_eq!(style.escape(ColorDepth::Ansi16), "\x1b[0;91;44m");
+        assert_eq!(
+            Style::default().bold().italic().escape(ColorDepth::Ansi16),
+            "\x1b[0;1;3;39;49m"
+        );
+    }
+}
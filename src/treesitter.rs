@@ -0,0 +1,227 @@
+//! Highlighting with tree-sitter, for file types that have a grammar compiled
+//! in. The tree is edited along with the buffer and reparsed incrementally the
+//! next time a row is highlighted.
+
+use ropey::Rope;
+use streaming_iterator::StreamingIterator;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
+
+use crate::syntax::Highlight;
+
+fn grammar(filetype: &str) -> Option<(Language, &'static str)> {
+    match filetype {
+        "rust" => Some((
+            tree_sitter_rust::LANGUAGE.into(),
+            tree_sitter_rust::HIGHLIGHTS_QUERY,
+        )),
+        _ => None,
+    }
+}
+
+/// Maps a capture name of a highlights query to a highlight class. Captures
+/// without one (operators, punctuation, variables) are drawn as `Normal`.
+fn capture_highlight(name: &str) -> Option<Highlight> {
+    let hl = match name {
+        "comment" | "comment.documentation" => Highlight::Comment,
+        "string" | "escape" => Highlight::String,
+        "keyword" => Highlight::Keyword1,
+        "type.builtin" => Highlight::Keyword2,
+        "type" | "constructor" => Highlight::Type,
+        "function" | "function.method" => Highlight::Function,
+        "function.macro" => Highlight::Macro,
+        "label" => Highlight::Lifetime,
+        "attribute" => Highlight::Attribute,
+        "constant" | "constant.builtin" => Highlight::Constant,
+        _ => return None,
+    };
+    Some(hl)
+}
+
+/// Byte offset and tree-sitter position of the char at `char_idx`.
+pub fn position(text: &Rope, char_idx: usize) -> (usize, Point) {
+    let byte = text.char_to_byte(char_idx);
+    let row = text.byte_to_line(byte);
+    (byte, Point::new(row, byte - text.line_to_byte(row)))
+}
+
+pub struct TreeSitter {
+    parser: Parser,
+    query: Query,
+    /// Highlight class of every capture of `query`, by capture index.
+    highlights: Vec<Option<Highlight>>,
+    tree: Option<Tree>,
+    /// Whether the text changed since `tree` was last parsed.
+    stale: bool,
+}
+
+impl TreeSitter {
+    /// Sets up a parser for `filetype`, or returns `None` if there is no
+    /// grammar for it.
+    pub fn new(filetype: &str) -> Option<Self> {
+        let (language, source) = grammar(filetype)?;
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, source).ok()?;
+        let highlights = query
+            .capture_names()
+            .iter()
+            .map(|name| capture_highlight(name))
+            .collect();
+        Some(TreeSitter {
+            parser,
+            query,
+            highlights,
+            tree: None,
+            stale: true,
+        })
+    }
+
+    /// Records that the bytes between `start` and `old_end` were replaced by
+    /// ones ending at `new_end`.
+    pub fn edit(
+        &mut self,
+        start: (usize, Point),
+        old_end: (usize, Point),
+        new_end: (usize, Point),
+    ) {
+        if let Some(tree) = &mut self.tree {
+            tree.edit(&InputEdit {
+                start_byte: start.0,
+                old_end_byte: old_end.0,
+                new_end_byte: new_end.0,
+                start_position: start.1,
+                old_end_position: old_end.1,
+                new_end_position: new_end.1,
+            });
+        }
+        self.stale = true;
+    }
+
+    fn parse(&mut self, text: &Rope) {
+        if !self.stale {
+            return;
+        }
+        let len = text.len_bytes();
+        self.tree = self.parser.parse_with_options(
+            &mut |byte, _| {
+                if byte >= len {
+                    return &[][..];
+                }
+                let (chunk, start, _, _) = text.chunk_at_byte(byte);
+                &chunk.as_bytes()[byte - start..]
+            },
+            self.tree.as_ref(),
+            None,
+        );
+        self.stale = false;
+    }
+
+    /// Highlight class of every char of row `at`, or `None` if the text
+    /// couldn't be parsed.
+    pub fn highlight_line(&mut self, text: &Rope, at: usize) -> Option<Vec<Highlight>> {
+        self.parse(text);
+        let tree = self.tree.as_ref()?;
+        let line = text.line(at);
+        let start = text.line_to_byte(at);
+        let end = start + line.len_bytes();
+
+        let mut bytes = vec![Highlight::Normal; line.len_bytes()];
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(start..end);
+        let source = |node: Node| {
+            text.byte_slice(node.byte_range())
+                .chunks()
+                .map(str::as_bytes)
+        };
+        let mut captures = cursor.captures(&self.query, tree.root_node(), source);
+        // Patterns are listed most specific first, so the first capture of a
+        // node wins; captures of nodes nested in it come later and override it.
+        let mut last = None;
+        while let Some((m, i)) = captures.next() {
+            let capture = m.captures[*i];
+            let mut node = capture.node;
+            let hl = match node.kind() {
+                "integer_literal" | "float_literal" => Some(Highlight::Number),
+                _ => self.highlights[capture.index as usize],
+            };
+            // The query only captures the name of a lifetime, not its quote.
+            if hl == Some(Highlight::Lifetime) {
+                node = node.parent().unwrap_or(node);
+            }
+            let range = node.byte_range();
+            if last.as_ref() == Some(&range) {
+                continue;
+            }
+            last = Some(range.clone());
+            let Some(hl) = hl else {
+                continue;
+            };
+            let from = range.start.max(start) - start;
+            let to = range.end.min(end) - start;
+            if from < to {
+                bytes[from..to].fill(hl);
+            }
+        }
+
+        let mut byte = 0;
+        let hl = line
+            .chars()
+            .take_while(|&c| c != '\n')
+            .map(|c| {
+                let hl = bytes[byte];
+                byte += c.len_utf8();
+                hl
+            })
+            .collect();
+        Some(hl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{buffer::Buffer, syntax::HLDB};
+
+    use super::*;
+
+    fn rust_buffer(text: &str) -> Buffer {
+        let mut buffer = Buffer::from_reader(text.as_bytes(), 4).unwrap();
+        buffer.set_syntax(Some(HLDB[0]));
+        buffer
+    }
+
+    fn hl(buffer: &mut Buffer, at: usize) -> Vec<Highlight> {
+        buffer.row(at).hl.clone()
+    }
+
+    #[test]
+    fn captures_map_to_highlights() {
+        let mut buffer = rust_buffer("#[test]\nfn f<'a>(x: &'a Foo) { println!(\"{}\", 0x1F); }\n");
+        assert!(hl(&mut buffer, 0)
+            .iter()
+            .all(|&h| h == Highlight::Attribute));
+        let row = hl(&mut buffer, 1);
+        assert_eq!(row[..2], [Highlight::Keyword1; 2]);
+        assert_eq!(row[3], Highlight::Function);
+        assert_eq!(row[5..7], [Highlight::Lifetime; 2]);
+        assert_eq!(row[16..19], [Highlight::Type; 3]);
+        assert_eq!(row[23..31], [Highlight::Macro; 8]);
+        assert_eq!(row[32..36], [Highlight::String; 4]);
+        assert_eq!(row[38..42], [Highlight::Number; 4]);
+    }
+
+    #[test]
+    fn edits_reparse_other_rows() {
+        let mut buffer = rust_buffer("let a = 1;\nlet b = 2;\n");
+        assert_eq!(hl(&mut buffer, 1)[0], Highlight::Keyword1);
+        buffer.insert_str(0, 0, "/*");
+        assert_eq!(hl(&mut buffer, 1)[0], Highlight::Comment);
+        buffer.del_char(0, 0);
+        assert_eq!(hl(&mut buffer, 1)[0], Highlight::Keyword1);
+    }
+
+    #[test]
+    fn tabs_repeat_the_highlight() {
+        let mut buffer = rust_buffer("//\tx\n");
+        assert_eq!(hl(&mut buffer, 0), [Highlight::Comment; 5]);
+    }
+}